@@ -0,0 +1,110 @@
+use std::{collections::BTreeMap, io::Read, iter};
+
+use color_eyre::{eyre::eyre, Result};
+use sled::Db;
+use tracing::{info, instrument};
+
+/// Every tree of a database with its keys and values hex encoded, so that compressed values and
+/// single-byte ratings survive the trip through JSON unchanged.
+#[derive(Debug, serde::Deserialize)]
+struct DatabaseDump {
+    trees: BTreeMap<String, Vec<(String, String)>>,
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(hex: &str) -> Result<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return Err(eyre!("odd length hex string in database dump"));
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            u8::from_str_radix(&hex[i..i + 2], 16)
+                .map_err(|_| eyre!("invalid hex string in database dump"))
+        })
+        .collect()
+}
+
+/// Names of the trees holding data, leaving out sled's own default tree.
+fn data_trees(db: &Db) -> Vec<String> {
+    db.tree_names()
+        .iter()
+        .filter(|name| name.as_ref() != b"__sled__default")
+        .map(|name| String::from_utf8_lossy(name).to_string())
+        .collect()
+}
+
+/// Every tree of the database as a single JSON document, produced piece by piece while
+/// iterating over the trees so that it never has to be held in memory whole.
+#[instrument(skip(db))]
+pub(crate) fn export_db(
+    db: &Db,
+) -> Result<impl Iterator<Item = sled::Result<String>> + Send + 'static> {
+    let trees = data_trees(db)
+        .into_iter()
+        .map(|name| Ok((serde_json::to_string(&name)?, db.open_tree(&name)?)))
+        .collect::<Result<Vec<_>>>()?;
+    info!(trees = trees.len(), "exporting database");
+    let body = trees.into_iter().enumerate().flat_map(|(i, (name, tree))| {
+        let separator = if i == 0 { "" } else { "," };
+        iter::once(Ok(format!("{}{}:[", separator, name)))
+            .chain(tree.iter().enumerate().map(|(j, it)| {
+                let (key, value) = it?;
+                let separator = if j == 0 { "" } else { "," };
+                Ok(format!(
+                    "{}[\"{}\",\"{}\"]",
+                    separator,
+                    to_hex(&key),
+                    to_hex(&value)
+                ))
+            }))
+            .chain(iter::once(Ok("]".to_string())))
+    });
+    Ok(iter::once(Ok(r#"{"trees":{"#.to_string()))
+        .chain(body)
+        .chain(iter::once(Ok("}}".to_string()))))
+}
+
+/// Replaces the database contents with a document written by [`export_db`], returning the
+/// number of entries restored.
+///
+/// Trees present in the database but absent from the document are dropped, so that the result
+/// matches the exported database exactly.
+#[instrument(skip(db, reader))]
+pub(crate) fn import_db(db: &Db, reader: impl Read) -> Result<usize> {
+    let dump: DatabaseDump = serde_json::from_reader(reader)?;
+    // decode everything before touching the database so a broken document changes nothing
+    let trees = dump
+        .trees
+        .into_iter()
+        .map(|(name, entries)| {
+            let entries = entries
+                .iter()
+                .map(|(key, value)| Ok((from_hex(key)?, from_hex(value)?)))
+                .collect::<Result<Vec<_>>>()?;
+            Ok((name, entries))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    for name in data_trees(db) {
+        if !trees.iter().any(|(restored, _)| *restored == name) {
+            db.drop_tree(&name)?;
+        }
+    }
+    let mut restored = 0;
+    for (name, entries) in trees {
+        let tree = db.open_tree(&name)?;
+        tree.clear()?;
+        let mut batch = sled::Batch::default();
+        restored += entries.len();
+        for (key, value) in entries {
+            batch.insert(key, value);
+        }
+        tree.apply_batch(batch)?;
+    }
+    db.flush()?;
+    info!(restored, "imported database");
+    Ok(restored)
+}
@@ -0,0 +1,160 @@
+use std::{future::Future, time::Duration};
+
+use axum::http::StatusCode;
+use rspotify::{http::HttpError, ClientError, ClientResult};
+use tracing::warn;
+
+/// Most attempts made at a rate limited request.
+const RETRY_ATTEMPTS: u32 = 5;
+/// Wait before retrying a rate limited request that didn't say how long to wait, doubled after
+/// every attempt.
+const RETRY_BACKOFF: Duration = Duration::from_secs(1);
+
+/// What went wrong with a Spotify request, as far as deciding how to react is concerned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ErrorKind {
+    /// Too many requests; Spotify asks to wait `retry_after` before the next one.
+    RateLimited {
+        retry_after: Option<Duration>,
+    },
+    /// The session was revoked or its refresh token rejected, which only a new login can fix.
+    Unauthorized,
+    NotFound,
+    Other,
+}
+
+pub(crate) fn classify(error: &ClientError) -> ErrorKind {
+    let response = match error {
+        ClientError::Http(http_error) => match http_error.as_ref() {
+            HttpError::StatusCode(response) => response,
+            _ => return ErrorKind::Other,
+        },
+        _ => return ErrorKind::Other,
+    };
+    match response.status() {
+        StatusCode::TOO_MANY_REQUESTS => ErrorKind::RateLimited {
+            retry_after: response
+                .headers()
+                .get("retry-after")
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.parse().ok())
+                .map(Duration::from_secs),
+        },
+        StatusCode::UNAUTHORIZED => ErrorKind::Unauthorized,
+        // the accounts service answers a rejected refresh token with a bad request
+        StatusCode::BAD_REQUEST if response.url().host_str() == Some("accounts.spotify.com") => {
+            ErrorKind::Unauthorized
+        }
+        StatusCode::NOT_FOUND => ErrorKind::NotFound,
+        _ => ErrorKind::Other,
+    }
+}
+
+/// A failed Spotify request together with its [`ErrorKind`].
+#[derive(Debug)]
+pub(crate) struct ClassifiedError {
+    pub(crate) kind: ErrorKind,
+    pub(crate) error: ClientError,
+}
+
+impl From<ClientError> for ClassifiedError {
+    fn from(error: ClientError) -> Self {
+        Self {
+            kind: classify(&error),
+            error,
+        }
+    }
+}
+
+impl std::fmt::Display for ClassifiedError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.error.fmt(f)
+    }
+}
+
+impl std::error::Error for ClassifiedError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+/// Attaches the kind of error to a Spotify result, for call sites that branch on it.
+pub(crate) fn classified<T>(result: ClientResult<T>) -> Result<T, ClassifiedError> {
+    result.map_err(ClassifiedError::from)
+}
+
+/// Makes the request, repeating it while Spotify rate limits it, waiting as long as asked to or
+/// backing off exponentially.
+pub(crate) async fn with_retries<T, F>(mut request: impl FnMut() -> F) -> ClientResult<T>
+where
+    F: Future<Output = ClientResult<T>>,
+{
+    let mut backoff = RETRY_BACKOFF;
+    let mut attempt = 1;
+    loop {
+        let error = match request().await {
+            Err(error) if attempt < RETRY_ATTEMPTS => error,
+            result => return result,
+        };
+        match classify(&error) {
+            ErrorKind::RateLimited { retry_after } => {
+                let wait = retry_after.unwrap_or(backoff);
+                warn!(attempt, ?wait, "rate limited by Spotify, retrying");
+                tokio::time::sleep(wait).await;
+                backoff *= 2;
+                attempt += 1;
+            }
+            _ => return Err(error),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn failed_request(status: StatusCode, retry_after: Option<&str>) -> ClientError {
+        let mut response = axum::http::Response::builder().status(status);
+        if let Some(retry_after) = retry_after {
+            response = response.header("retry-after", retry_after);
+        }
+        let response = reqwest::Response::from(response.body("").unwrap());
+        ClientError::Http(Box::new(HttpError::StatusCode(response)))
+    }
+
+    #[test]
+    fn failed_requests_are_classified_by_status() {
+        for (status, retry_after, kind) in [
+            (
+                StatusCode::TOO_MANY_REQUESTS,
+                Some("3"),
+                ErrorKind::RateLimited {
+                    retry_after: Some(Duration::from_secs(3)),
+                },
+            ),
+            (
+                StatusCode::TOO_MANY_REQUESTS,
+                None,
+                ErrorKind::RateLimited { retry_after: None },
+            ),
+            (StatusCode::UNAUTHORIZED, None, ErrorKind::Unauthorized),
+            (StatusCode::NOT_FOUND, None, ErrorKind::NotFound),
+            // only the accounts service means a rejected refresh token by a bad request
+            (StatusCode::BAD_REQUEST, None, ErrorKind::Other),
+            (StatusCode::INTERNAL_SERVER_ERROR, None, ErrorKind::Other),
+        ] {
+            assert_eq!(
+                classify(&failed_request(status, retry_after)),
+                kind,
+                "{}",
+                status
+            );
+        }
+    }
+
+    #[test]
+    fn errors_without_a_response_are_other() {
+        let error = ClientError::ParseJson(serde_json::from_str::<u8>("").unwrap_err());
+        assert_eq!(classify(&error), ErrorKind::Other);
+    }
+}
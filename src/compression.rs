@@ -0,0 +1,102 @@
+use std::borrow::Cow;
+
+use color_eyre::{eyre::eyre, Result};
+use sled::{transaction::TransactionError, Db, Transactional};
+use tracing::{info, instrument};
+
+/// `meta` key present when the values of [`COMPRESSED_TREES`] are zstd compressed.
+const COMPRESSED_KEY: &str = "compressed";
+/// Trees holding the bulky JSON values that get compressed.
+const COMPRESSED_TREES: [&str; 2] = ["track_details", "track_features"];
+const ZSTD_LEVEL: i32 = 3;
+
+/// Encoding of the values in [`COMPRESSED_TREES`], which every read and write of them goes
+/// through.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Codec {
+    compressed: bool,
+}
+
+impl Codec {
+    /// The codec the database was set up with.
+    pub(crate) fn of(db: &Db) -> Result<Self> {
+        Ok(Self {
+            compressed: db.open_tree("meta")?.contains_key(COMPRESSED_KEY)?,
+        })
+    }
+
+    pub(crate) fn encode(&self, value: &[u8]) -> Result<Vec<u8>> {
+        if self.compressed {
+            Ok(zstd::encode_all(value, ZSTD_LEVEL)?)
+        } else {
+            Ok(value.to_vec())
+        }
+    }
+
+    pub(crate) fn decode<'a>(&self, value: &'a [u8]) -> Result<Cow<'a, [u8]>> {
+        if self.compressed {
+            Ok(Cow::Owned(zstd::decode_all(value)?))
+        } else {
+            Ok(Cow::Borrowed(value))
+        }
+    }
+}
+
+/// Compresses the stored values once `DEFY_COMPRESS` is set, recording it in `meta`.
+///
+/// A compressed database stays compressed even if the variable is unset later.
+#[instrument(skip(db))]
+pub(crate) fn setup(db: &Db) -> Result<()> {
+    if Codec::of(db)?.compressed {
+        return Ok(());
+    }
+    if std::env::var("DEFY_COMPRESS").is_err() {
+        return Ok(());
+    }
+    info!("compressing database values");
+    let trees = [
+        db.open_tree(COMPRESSED_TREES[0])?,
+        db.open_tree(COMPRESSED_TREES[1])?,
+        db.open_tree("meta")?,
+    ];
+    let compressed = trees[..COMPRESSED_TREES.len()]
+        .iter()
+        .map(|tree| {
+            tree.iter()
+                .map(|it| {
+                    let (key, value) = it?;
+                    Ok((key, zstd::encode_all(&value[..], ZSTD_LEVEL)?))
+                })
+                .collect::<Result<Vec<_>>>()
+        })
+        .collect::<Result<Vec<_>>>()?;
+    // the values and the marker change together so no reader sees them disagree
+    (&trees[..])
+        .transaction(|trees| {
+            for (tree, values) in trees.iter().zip(&compressed) {
+                for (key, value) in values {
+                    tree.insert(key, value.as_slice())?;
+                }
+            }
+            trees[COMPRESSED_TREES.len()].insert(COMPRESSED_KEY, sled::IVec::default())?;
+            Ok(())
+        })
+        .map_err(|error: TransactionError| eyre!("compressing database failed: {}", error))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn values_survive_a_round_trip() {
+        let value = br#"{"danceability":0.5,"energy":0.5,"valence":0.5}"#.repeat(20);
+        for compressed in [false, true] {
+            let codec = Codec { compressed };
+            let encoded = codec.encode(&value).unwrap();
+            assert_eq!(encoded.len() < value.len(), compressed);
+            assert_eq!(&codec.decode(&encoded).unwrap()[..], &value[..]);
+        }
+    }
+}
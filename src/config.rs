@@ -0,0 +1,111 @@
+use std::{collections::HashSet, path::PathBuf};
+
+use color_eyre::{
+    eyre::{eyre, WrapErr},
+    Result,
+};
+use tracing::{debug, instrument};
+
+use crate::learning::FeatureThreshold;
+
+/// Settings read from `~/.config/defy/config.toml`, each overridden by its environment variable.
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Config {
+    client_id: Option<String>,
+    client_secret: Option<String>,
+    redirect_uri: Option<String>,
+    scopes: Option<HashSet<String>>,
+    rolling_filters: Option<Vec<String>>,
+    priority_contributors: Option<Vec<String>>,
+}
+
+impl Config {
+    fn path() -> Option<PathBuf> {
+        std::env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+            .map(|config_home| config_home.join("defy").join("config.toml"))
+    }
+
+    /// Reads the config file, falling back to an empty config when there is none.
+    #[instrument]
+    pub(crate) fn load() -> Result<Self> {
+        let path = match Self::path() {
+            Some(path) if path.exists() => path,
+            _ => return Ok(Self::default()),
+        };
+        debug!(?path, "reading config file");
+        let contents = std::fs::read_to_string(&path)
+            .wrap_err_with(|| format!("cannot read {}", path.display()))?;
+        toml::from_str(&contents).wrap_err_with(|| format!("cannot parse {}", path.display()))
+    }
+
+    pub(crate) fn credentials(&self) -> Result<rspotify::Credentials> {
+        let id = env_or("RSPOTIFY_CLIENT_ID", &self.client_id);
+        let secret = env_or("RSPOTIFY_CLIENT_SECRET", &self.client_secret);
+        match (id, secret) {
+            (Some(id), Some(secret)) => Ok(rspotify::Credentials::new(&id, &secret)),
+            (id, secret) => {
+                let missing: Vec<&str> = [
+                    (id.is_none(), "client_id (RSPOTIFY_CLIENT_ID)"),
+                    (secret.is_none(), "client_secret (RSPOTIFY_CLIENT_SECRET)"),
+                ]
+                .iter()
+                .filter(|(missing, _)| *missing)
+                .map(|(_, field)| *field)
+                .collect();
+                Err(eyre!(
+                    "missing credentials in env and config file: {}",
+                    missing.join(", ")
+                ))
+            }
+        }
+    }
+
+    pub(crate) fn redirect_uri(&self) -> Option<String> {
+        env_or("RSPOTIFY_REDIRECT_URI", &self.redirect_uri)
+    }
+
+    pub(crate) fn scopes(&self) -> Option<HashSet<String>> {
+        self.scopes.clone()
+    }
+
+    /// Audio feature thresholds every rolling playlist track must pass, e.g.
+    /// `danceability >= 0.6`, overridden by the comma separated `DEFY_ROLLING_FILTERS`.
+    pub(crate) fn rolling_filters(&self) -> Result<Vec<FeatureThreshold>> {
+        let filters = match std::env::var("DEFY_ROLLING_FILTERS") {
+            Ok(filters) => filters
+                .split(',')
+                .map(str::trim)
+                .filter(|filter| !filter.is_empty())
+                .map(str::to_string)
+                .collect(),
+            Err(_) => self.rolling_filters.clone().unwrap_or_default(),
+        };
+        filters.iter().map(|filter| filter.parse()).collect()
+    }
+
+    /// Ids of the users whose additions to the main playlist go into the rolling playlist first,
+    /// overridden by the comma separated `DEFY_PRIORITY_CONTRIBUTORS`.
+    pub(crate) fn priority_contributors(&self) -> HashSet<String> {
+        match std::env::var("DEFY_PRIORITY_CONTRIBUTORS") {
+            Ok(contributors) => contributors
+                .split(',')
+                .map(str::trim)
+                .filter(|contributor| !contributor.is_empty())
+                .map(str::to_string)
+                .collect(),
+            Err(_) => self
+                .priority_contributors
+                .iter()
+                .flatten()
+                .cloned()
+                .collect(),
+        }
+    }
+}
+
+fn env_or(name: &str, fallback: &Option<String>) -> Option<String> {
+    std::env::var(name).ok().or_else(|| fallback.clone())
+}
@@ -1,29 +1,178 @@
-use std::sync::Arc;
+use std::{
+    collections::{BTreeMap, HashMap},
+    iter,
+    net::{IpAddr, SocketAddr},
+    ops::Bound,
+    sync::Arc,
+    time::Duration,
+};
 
 use axum::{
-    error_handling::HandleErrorExt,
-    extract::{Extension, Path},
-    response::{Html, IntoResponse},
-    routing::{get, post, service_method_routing},
+    body::StreamBody,
+    extract::{Extension, Path, Query},
+    http::StatusCode,
+    response::{Headers, Html, IntoResponse, Redirect},
+    routing::{get, post, put, service_method_routing},
     AddExtensionLayer, Json, Router,
 };
-use color_eyre::eyre::{eyre, Context};
-use rspotify::{clients::BaseClient, model::SimplifiedTrack};
-use sled::Db;
+use color_eyre::eyre::{eyre, WrapErr};
+use ndarray::Array2;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use rspotify::{
+    clients::{BaseClient, OAuthClient},
+    model::{
+        Device, Id, Image, PlayableId, PlaylistId, SimplifiedTrack, SubscriptionLevel, TrackId,
+    },
+};
+use sled::{transaction::TransactionError, Db, Transactional};
 use tokio::sync::{oneshot, Mutex};
 use tower_http::{services::ServeDir, trace::TraceLayer};
-use tracing::instrument;
+use tracing::{debug, info, instrument, warn};
+
+use crate::{
+    client_error::{classify, ClassifiedError, ErrorKind},
+    compression::Codec,
+    governor::Governor,
+    kickstart::{self, CallbackState, Client},
+    learning,
+    source::Governed,
+};
+
+type Result<T> = std::result::Result<T, DataInputError>;
+
+/// Directory the frontend assets are served from, relative to the working directory.
+const STATIC_DIR: &str = "static";
+/// Frontend assets built into the binary, served when there is no [`STATIC_DIR`].
+const EMBEDDED_ASSETS: &[(&str, &str, &str)] = &[(
+    "data_input.js",
+    "application/javascript",
+    include_str!("../static/data_input.js"),
+)];
+
+/// Key under which the last returned track id is kept in a `cursor/{feature}` tree.
+const CURSOR_KEY: &str = "position";
+/// Highest rating accepted, leaving room for a graded strength of fit above plain 0/1 labels.
+const MAX_RATING: u8 = 5;
+/// How often the token expiry is checked when `DEFY_TOKEN_REFRESH_MINUTES` is not set.
+const DEFAULT_TOKEN_REFRESH_MINUTES: u64 = 10;
 
-use crate::kickstart::Client;
+#[derive(Clone)]
+struct State {
+    db: Db,
+    client: Client,
+    /// Shared with the rest of the program, so every direct `client` call acquires it first.
+    governor: Governor,
+    shutdown_mechanism: Arc<Mutex<Option<oneshot::Sender<()>>>>,
+    /// Source of randomness for track selection, seeded from `DEFY_RANDOM_SEED` if set.
+    ///
+    /// Seeded runs start without the cursors stored by earlier runs, so that runs with the
+    /// same seed offer tracks in the same order.
+    rng: Arc<Mutex<StdRng>>,
+    models: Arc<Mutex<ModelCache>>,
+    /// Whether the account may control playback, checked once when the interface starts.
+    playback_allowed: bool,
+}
 
-// type Result<T> = std::result::Result<T, String>;
-type Result<T> = std::result::Result<T, StringableReport>;
-type State = (Db, Client, Arc<Mutex<Option<oneshot::Sender<()>>>>);
+/// Models fitted by the interface, reused until the ratings of their feature change.
+#[derive(Default)]
+struct ModelCache {
+    /// Bumped whenever a feature's ratings change.
+    versions: HashMap<String, u64>,
+    /// Each model together with the version of the ratings it was fitted on.
+    models: HashMap<String, (u64, Arc<learning::Model>)>,
+}
+
+/// Marks the cached model of the feature as stale after its ratings changed.
+async fn invalidate_model(models: &Mutex<ModelCache>, feature_id: &str) {
+    *models
+        .lock()
+        .await
+        .versions
+        .entry(feature_id.to_string())
+        .or_default() += 1;
+}
+
+/// Marks every cached model as stale, e.g. after the whole database was replaced.
+async fn invalidate_all_models(models: &Mutex<ModelCache>) {
+    let mut cache = models.lock().await;
+    let features: Vec<String> = cache.models.keys().cloned().collect();
+    for feature_id in features {
+        *cache.versions.entry(feature_id).or_default() += 1;
+    }
+}
+
+/// The model fitted on the feature's current ratings, fitting it only if they changed.
+async fn cached_model(
+    db: &Db,
+    models: &Mutex<ModelCache>,
+    feature_id: &str,
+) -> Result<Arc<learning::Model>> {
+    let version = {
+        let cache = models.lock().await;
+        let version = cache.versions.get(feature_id).copied().unwrap_or_default();
+        match cache.models.get(feature_id) {
+            Some((fitted_version, model)) if *fitted_version == version => return Ok(model.clone()),
+            _ => version,
+        }
+    };
+    let dataset = learning::feature_dataset_for_fitting(db.clone(), feature_id).await?;
+    let model = Arc::new(learning::fit(&dataset, learning::Algorithm::from_env()?)?);
+    // ratings changed while fitting keep the stale model out of the cache
+    let mut cache = models.lock().await;
+    if cache.versions.get(feature_id).copied().unwrap_or_default() == version {
+        cache
+            .models
+            .insert(feature_id.to_string(), (version, model.clone()));
+    }
+    Ok(model)
+}
 
 #[instrument(skip(db))]
-pub(crate) async fn web_interface(db: Db, client: Client) -> color_eyre::Result<()> {
+pub(crate) async fn web_interface(
+    db: Db,
+    client: Client,
+    governor: Governor,
+    bind: IpAddr,
+) -> color_eyre::Result<()> {
+    let serve_from_disk = std::path::Path::new(STATIC_DIR).is_dir();
+    if !serve_from_disk {
+        info!(
+            cwd = ?std::env::current_dir().ok(),
+            "no {} directory in the working directory, serving embedded assets",
+            STATIC_DIR
+        );
+    }
     let (shutdown_tx, shutdown_rx) = oneshot::channel();
-    let state: State = (db, client, Arc::new(Mutex::new(Some(shutdown_tx))));
+    let rng = match std::env::var("DEFY_RANDOM_SEED") {
+        Ok(seed) => {
+            let rng =
+                StdRng::seed_from_u64(seed.parse().wrap_err("cannot parse DEFY_RANDOM_SEED")?);
+            forget_cursors(&db)?;
+            rng
+        }
+        Err(_) => StdRng::from_entropy(),
+    };
+    let refresh_period = Duration::from_secs(
+        60 * std::env::var("DEFY_TOKEN_REFRESH_MINUTES")
+            .map_or(Ok(DEFAULT_TOKEN_REFRESH_MINUTES), |minutes| minutes.parse())
+            .wrap_err("cannot parse DEFY_TOKEN_REFRESH_MINUTES")?,
+    );
+    let refresher = tokio::spawn(keep_token_fresh(
+        client.clone(),
+        governor.clone(),
+        refresh_period,
+    ));
+    let callback_state: CallbackState = (client.clone(), Arc::new(Mutex::new(None)));
+    let playback_allowed = check_playback_allowed(&client, &governor).await;
+    let state = State {
+        db,
+        client,
+        governor,
+        shutdown_mechanism: Arc::new(Mutex::new(Some(shutdown_tx))),
+        rng: Arc::new(Mutex::new(rng)),
+        models: Arc::default(),
+        playback_allowed,
+    };
 
     let app = Router::new()
         .nest(
@@ -44,46 +193,235 @@ pub(crate) async fn web_interface(db: Db, client: Client) -> color_eyre::Result<
                                         )
                                         .nest(
                                             "/:track_id",
-                                            Router::new().route(
-                                                "/rate/:rating",
-                                                post(rate_feature_for_track),
-                                            ),
+                                            Router::new()
+                                                .route(
+                                                    "/rate/:rating",
+                                                    post(rate_feature_for_track),
+                                                )
+                                                .route("/skip", post(skip_track_for_feature)),
                                         ),
                                 )
+                                .route("/next_batch", get(next_batch_for_feature))
+                                .route("/rate", post(rate_feature_for_tracks))
+                                .route("/playlist.m3u", get(feature_playlist_m3u))
+                                .route("/reset", post(reset_feature))
+                                .route("/rename", post(rename_feature))
+                                .route("/importance", get(feature_importance))
+                                .route("/evaluate", get(evaluate_feature))
+                                .route("/roc", get(feature_roc))
+                                .route("/model/metadata", get(feature_model_metadata))
+                                .route("/similar", get(similar_to_feature))
+                                .route("/uncertain", get(uncertain_for_feature))
+                                .route("/threshold", put(set_feature_threshold))
+                                .route("/dump", get(dump_feature))
+                                .route("/restore", post(restore_feature))
                                 .route("/", post(create_feature)),
                         )
+                        .route("/query", post(query_features))
                         .route("/", get(list_features)),
                 )
+                .nest(
+                    "/tracks",
+                    Router::new()
+                        .route("/details", post(tracks_details))
+                        .route("/:track_id/fetch", post(fetch_track))
+                        .route("/:track_id/features", get(track_features))
+                        .route("/:track_id/raw_features", get(raw_track_features)),
+                )
+                .route("/update/preview", get(update_preview))
+                .nest(
+                    "/analysis",
+                    Router::new()
+                        .route("/histogram/:feature", get(histogram))
+                        .route("/missing_features", get(missing_features))
+                        .route("/clusters", get(clusters)),
+                )
+                .route("/me", get(me))
+                .route("/devices", get(devices))
+                .route("/play", put(play))
+                .nest(
+                    "/playback",
+                    Router::new()
+                        .route("/pause", put(pause_playback))
+                        .route("/resume", put(resume_playback))
+                        .route("/seek/:position_ms", post(seek_playback)),
+                )
+                .route("/backup", get(backup))
+                .route("/restore", post(restore))
+                .route("/summary", get(summary))
+                .route("/health", get(health))
                 .route("/spotify_token", get(spotify_token))
-                .route("/shutdown", post(shutdown)),
+                .route("/shutdown", post(shutdown))
+                .route("/reauthorize", get(reauthorize))
+                .route("/callback", get(kickstart::auth_callback)),
         )
-        .route("/", get(data_input_html))
-        .nest(
+        .route("/", get(data_input_html));
+    // assets on disk are served as they are, so frontend changes need no rebuild
+    let app = if serve_from_disk {
+        app.nest(
             "/static",
-            service_method_routing::get(ServeDir::new("static"))
-                .handle_error(|error: std::io::Error| StringableReport(error.into())),
+            service_method_routing::get(ServeDir::new(STATIC_DIR)).handle_error(
+                |error: std::io::Error| {
+                    DataInputError::Internal(eyre!(
+                        "cannot serve static file from {}: {}",
+                        STATIC_DIR,
+                        error
+                    ))
+                },
+            ),
         )
+        .boxed()
+    } else {
+        app.route("/static/:file", get(static_asset)).boxed()
+    };
+    let app = app
         .layer(AddExtensionLayer::new(state))
+        .layer(AddExtensionLayer::new(callback_state))
         .layer(TraceLayer::new_for_http());
-    let bound_server = axum::Server::bind(
-        &"127.0.0.1:3000"
-            .parse()
-            .wrap_err("cannot parse bind address")?,
-    )
-    .serve(app.into_make_service());
+    let address = SocketAddr::new(bind, crate::port_from_env("DEFY_UI_PORT"));
+    let bound_server = axum::Server::bind(&address).serve(app.into_make_service());
 
-    webbrowser::open("http://127.0.0.1:3000/")?;
+    crate::open_in_browser(&format!("http://{}/", address));
 
-    bound_server
+    let served = bound_server
         .with_graceful_shutdown(async move {
             shutdown_rx.await.unwrap();
         })
-        .await?;
+        .await;
+    refresher.abort();
+    Ok(served?)
+}
+
+/// Whether the account is premium and the token has the `streaming` scope, both of which playback
+/// control needs. A failed check allows playback, leaving Spotify to refuse it.
+#[instrument(skip(client, governor))]
+async fn check_playback_allowed(client: &Client, governor: &Governor) -> bool {
+    let has_streaming = client
+        .get_token()
+        .lock()
+        .await
+        .unwrap()
+        .as_ref()
+        .map_or(false, |token| token.scopes.contains("streaming"));
+    if !has_streaming {
+        warn!("token lacks the streaming scope, disabling playback control");
+        return false;
+    }
+    let user = {
+        let _permit = governor.acquire().await;
+        client.current_user().await
+    };
+    match user {
+        Ok(user) if user.product == Some(SubscriptionLevel::Premium) => true,
+        Ok(user) => {
+            warn!(product = ?user.product, "account is not premium, disabling playback control");
+            false
+        }
+        Err(error) => {
+            warn!(%error, "checking the account's subscription failed");
+            true
+        }
+    }
+}
+
+/// Refuses playback control up front when the startup check found it unavailable.
+fn require_playback(playback_allowed: bool) -> Result<()> {
+    if playback_allowed {
+        Ok(())
+    } else {
+        Err(DataInputError::PremiumRequired)
+    }
+}
+
+/// Refreshes the token whenever it would expire before the next check, so that the first
+/// request after an idle period does not wait on a refresh and a revoked session shows up early.
+#[instrument(skip(client, governor))]
+async fn keep_token_fresh(client: Client, governor: Governor, period: Duration) {
+    let margin = chrono::Duration::from_std(period).expect("token refresh period out of range");
+    let mut interval = tokio::time::interval(period);
+    loop {
+        interval.tick().await;
+        let expires_at = client
+            .get_token()
+            .lock()
+            .await
+            .unwrap()
+            .as_ref()
+            .and_then(|token| token.expires_at);
+        match expires_at {
+            Some(expires_at) if expires_at - margin <= chrono::Utc::now() => {}
+            _ => continue,
+        }
+        let _permit = governor.acquire().await;
+        match client.refresh_token().await {
+            Ok(()) => debug!("refreshed Spotify token"),
+            Err(error) => warn!(%error, "refreshing Spotify token failed"),
+        }
+    }
+}
+
+/// Opens the ratings tree of an existing feature.
+fn open_feature_tree(db: &Db, feature_id: &str) -> Result<sled::Tree> {
+    if !feature_exists(db, feature_id) {
+        return Err(DataInputError::FeatureNotFound(feature_id.to_string()));
+    }
+    Ok(db.open_tree(format!("input/{}", feature_id))?)
+}
+
+/// Rejects feature ids outside `[A-Za-z0-9_-]+`, which could escape the `input/` namespace.
+fn validate_feature_id(feature_id: &str) -> Result<()> {
+    if feature_id.is_empty()
+        || !feature_id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    {
+        return Err(DataInputError::InvalidInput(format!(
+            "invalid feature id {:?}",
+            feature_id
+        )));
+    }
     Ok(())
 }
 
+/// Names of the database's own trees and namespaces, which features must not be named after.
+const RESERVED_FEATURE_NAMES: &[&str] = &[
+    "track_details",
+    "track_features",
+    "track_extras",
+    "track_added_by",
+    "album_art",
+    "clusters",
+    "meta",
+    "models",
+    "input",
+    "cursor",
+];
+
+/// Validates the id of a feature about to be created, which on top of [`validate_feature_id`]
+/// must not be one of [`RESERVED_FEATURE_NAMES`].
+fn validate_new_feature_id(feature_id: &str) -> Result<()> {
+    validate_feature_id(feature_id)?;
+    if RESERVED_FEATURE_NAMES
+        .iter()
+        .any(|reserved| reserved.eq_ignore_ascii_case(feature_id))
+    {
+        return Err(DataInputError::InvalidInput(format!(
+            "feature id {:?} is reserved",
+            feature_id
+        )));
+    }
+    Ok(())
+}
+
+fn feature_exists(db: &Db, feature_id: &str) -> bool {
+    let name = format!("input/{}", feature_id);
+    db.tree_names()
+        .iter()
+        .any(|tree| tree.as_ref() == name.as_bytes())
+}
+
 #[instrument(skip(db))]
-async fn list_features(Extension((db, _, _)): Extension<State>) -> Result<Json<Vec<String>>> {
+async fn list_features(Extension(State { db, .. }): Extension<State>) -> Result<Json<Vec<String>>> {
     Ok(Json(
         db.tree_names()
             .into_iter()
@@ -93,66 +431,1460 @@ async fn list_features(Extension((db, _, _)): Extension<State>) -> Result<Json<V
     ))
 }
 
+#[derive(Debug, serde::Serialize)]
+struct FeatureInfo {
+    name: String,
+    rated: usize,
+    /// Unix timestamp of the newest rating, if any rating carries one.
+    last_rated_at: Option<i64>,
+}
+
+impl FeatureInfo {
+    fn of(db: &Db, feature_id: &str) -> Result<Self> {
+        Ok(Self {
+            name: feature_id.to_string(),
+            rated: db.open_tree(format!("input/{}", feature_id))?.len(),
+            last_rated_at: crate::stats::last_rated_at(db, feature_id)?,
+        })
+    }
+}
+
 #[instrument(skip(db))]
 async fn create_feature(
-    Extension((db, _, _)): Extension<State>,
+    Extension(State { db, .. }): Extension<State>,
     Path(feature_id): Path<String>,
-) -> Result<&'static str> {
-    db.open_tree(format!("input/{}", feature_id))?;
-    Ok("ok")
+) -> Result<Json<FeatureInfo>> {
+    validate_new_feature_id(&feature_id)?;
+    if !feature_exists(&db, &feature_id) {
+        db.drop_tree(format!("cursor/{}", feature_id))?;
+    }
+    Ok(Json(FeatureInfo::of(&db, &feature_id)?))
 }
 
-#[instrument(skip(db))]
+/// Markets from the comma separated `DEFY_MARKET`, tracks offered for labeling must be
+/// available in at least one of them.
+///
+/// This is only applied when labeling; population stores every track and its features
+/// regardless of market.
+fn labeling_markets() -> Vec<String> {
+    std::env::var("DEFY_MARKET")
+        .unwrap_or_else(|_| "PL".to_string())
+        .split(',')
+        .map(|market| market.trim().to_string())
+        .filter(|market| !market.is_empty())
+        .collect()
+}
+
+/// Whether the track can be played in any of the markets, treating a missing market list as
+/// everywhere.
+fn is_available(details: &SimplifiedTrack, markets: &[String]) -> bool {
+    match &details.available_markets {
+        Some(available_markets) if !available_markets.is_empty() => available_markets
+            .iter()
+            .any(|available_market| markets.contains(available_market)),
+        _ => true,
+    }
+}
+
+/// Optional restrictions on the tracks offered for labeling, on top of the always applied
+/// market availability and audio features checks.
+#[derive(Debug, Default, serde::Deserialize)]
+struct EligibilityFilter {
+    /// Shortest accepted track, in milliseconds.
+    min_duration: Option<u64>,
+    /// Longest accepted track, in milliseconds.
+    max_duration: Option<u64>,
+    /// Whether only explicit (`true`) or only clean (`false`) tracks are accepted.
+    explicit: Option<bool>,
+}
+
+impl EligibilityFilter {
+    fn accepts(&self, details: &SimplifiedTrack) -> bool {
+        let duration = details.duration.as_millis() as u64;
+        self.min_duration.map_or(true, |min| duration >= min)
+            && self.max_duration.map_or(true, |max| duration <= max)
+            && self
+                .explicit
+                .map_or(true, |explicit| details.explicit == explicit)
+    }
+}
+
+/// Track offered for labeling, with a full-track URI to play when there is no preview.
+#[derive(Debug, serde::Serialize)]
+struct PlayableTrack {
+    #[serde(flatten)]
+    details: SimplifiedTrack,
+    play_uri: Option<String>,
+    /// Cover art of the track's album in the sizes Spotify provides, largest first.
+    images: Vec<Image>,
+}
+
+#[instrument(skip(db, client, governor, rng))]
 async fn random_untrained_track_for_feature(
-    Extension((db, _, _)): Extension<State>,
+    Extension(State {
+        db,
+        client,
+        governor,
+        rng,
+        ..
+    }): Extension<State>,
     Path(feature_id): Path<String>,
-) -> Result<Json<SimplifiedTrack>> {
+    Query(filter): Query<EligibilityFilter>,
+) -> Result<Json<PlayableTrack>> {
+    let mut tracks =
+        untrained_tracks(&db, &client, &governor, &rng, &feature_id, &filter, 1).await?;
+    Ok(Json(tracks.remove(0)))
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct BatchQuery {
+    count: Option<usize>,
+}
+
+/// Several untrained tracks at once, for the frontend to queue up.
+#[instrument(skip(db, client, governor, rng))]
+async fn next_batch_for_feature(
+    Extension(State {
+        db,
+        client,
+        governor,
+        rng,
+        ..
+    }): Extension<State>,
+    Path(feature_id): Path<String>,
+    Query(BatchQuery { count }): Query<BatchQuery>,
+    Query(filter): Query<EligibilityFilter>,
+) -> Result<Json<Vec<PlayableTrack>>> {
+    let count = count.unwrap_or(10);
+    if count == 0 {
+        return Err(DataInputError::InvalidInput(
+            "count must be positive".to_string(),
+        ));
+    }
+    Ok(Json(
+        untrained_tracks(&db, &client, &governor, &rng, &feature_id, &filter, count).await?,
+    ))
+}
+
+/// Up to `count` distinct eligible tracks not yet rated for the feature, continuing the scan
+/// of the feature's cursor and moving it past the last returned track.
+async fn untrained_tracks(
+    db: &Db,
+    client: &Client,
+    governor: &Governor,
+    rng: &Mutex<StdRng>,
+    feature_id: &str,
+    filter: &EligibilityFilter,
+    count: usize,
+) -> Result<Vec<PlayableTrack>> {
+    validate_feature_id(feature_id)?;
     let details_tree = db.open_tree("track_details")?;
     let features_tree = db.open_tree("track_features")?;
-    let feature_tree = db.open_tree(format!("input/{}", feature_id))?;
-    let null_ivec = sled::IVec::from(serde_json::to_vec(&serde_json::Value::Null)?);
-    for it in details_tree.iter() {
+    let feature_tree = open_feature_tree(db, feature_id)?;
+    let markets = labeling_markets();
+    let cursor_tree = db.open_tree(format!("cursor/{}", feature_id))?;
+    let codec = Codec::of(db)?;
+    let null_json = serde_json::to_vec(&serde_json::Value::Null)?;
+    let start = match cursor_tree.get(CURSOR_KEY)? {
+        Some(position) => Some(position),
+        // without a cursor, start the scan at a random track
+        None => match details_tree.len() {
+            0 => None,
+            len => {
+                let skip = rng.lock().await.gen_range(0..len);
+                details_tree.iter().keys().nth(skip).transpose()?
+            }
+        },
+    };
+    // resume scanning after the previously returned track, wrapping around to the start, so
+    // every track is visited at most once
+    let scan: Box<dyn Iterator<Item = sled::Result<(sled::IVec, sled::IVec)>>> = match start {
+        Some(position) => Box::new(
+            details_tree
+                .range((Bound::Excluded(position.clone()), Bound::Unbounded))
+                .chain(details_tree.range(..=position)),
+        ),
+        None => Box::new(details_tree.iter()),
+    };
+    let mut tracks = vec![];
+    for it in scan {
         let (id, details_vec) = it?;
         if !feature_tree.contains_key(&id)? {
-            match features_tree.get(id)? {
+            match features_tree.get(&id)? {
                 None => continue,
-                Some(val) if val == null_ivec => continue,
+                Some(val) if *codec.decode(&val)? == *null_json => continue,
                 Some(_) => (),
             }
-            let details: SimplifiedTrack = serde_json::from_slice(&details_vec)?;
-            if !details
-                .available_markets
-                .as_ref()
-                .map_or(false, |available_markets| {
-                    available_markets.iter().any(|market| market == "PL")
-                })
-            {
+            let details: SimplifiedTrack = serde_json::from_slice(&codec.decode(&details_vec)?)?;
+            if !is_available(&details, &markets) || !filter.accepts(&details) {
                 continue;
             }
-            return Ok(Json(details));
+            let play_uri = match &details.preview_url {
+                Some(_) => None,
+                None => Some(format!("spotify:track:{}", String::from_utf8_lossy(&id))),
+            };
+            cursor_tree.insert(CURSOR_KEY, &id)?;
+            let images = album_images(db, client, governor, &String::from_utf8_lossy(&id)).await?;
+            tracks.push(PlayableTrack {
+                details,
+                play_uri,
+                images,
+            });
+            if tracks.len() == count {
+                break;
+            }
         }
     }
-    Err(eyre!("no more tracks").into())
+    if tracks.is_empty() {
+        cursor_tree.remove(CURSOR_KEY)?;
+        return Err(DataInputError::NoMoreTracks);
+    }
+    Ok(tracks)
 }
 
-#[instrument(skip(db))]
+/// Drops the labeling cursor of every feature, so that the next scan of each starts where the
+/// random number generator puts it.
+fn forget_cursors(db: &Db) -> sled::Result<()> {
+    for name in db.tree_names() {
+        if name.starts_with(b"cursor/") {
+            db.drop_tree(name)?;
+        }
+    }
+    Ok(())
+}
+
+/// Album cover art of a stored track. Tracks stored without it, e.g. by older versions, get it
+/// fetched from Spotify once and cached in the `album_art` tree.
+async fn album_images(
+    db: &Db,
+    client: &Client,
+    governor: &Governor,
+    track_id: &str,
+) -> Result<Vec<Image>> {
+    let album_art_tree = db.open_tree("album_art")?;
+    if let Some(images) = album_art_tree.get(track_id)? {
+        return Ok(serde_json::from_slice(&images)?);
+    }
+    let id = TrackId::from_id(track_id)
+        .map_err(|_| DataInputError::InvalidInput(format!("invalid track id {}", track_id)))?;
+    let track = {
+        let _permit = governor.acquire().await;
+        client.track(&id).await
+    };
+    match track {
+        Ok(track) => {
+            album_art_tree.insert(track_id, serde_json::to_vec(&track.album.images)?)?;
+            Ok(track.album.images)
+        }
+        // cover art is a nicety, so a failed lookup leaves it out instead of failing labeling
+        Err(error) => {
+            warn!(%error, track_id, "fetching album art failed");
+            Ok(vec![])
+        }
+    }
+}
+
+#[instrument(skip(db, models))]
 async fn rate_feature_for_track(
-    Extension((db, _, _)): Extension<State>,
+    Extension(State { db, models, .. }): Extension<State>,
     Path((feature_id, track_id, rating)): Path<(String, String, u8)>,
 ) -> Result<&'static str> {
+    validate_feature_id(&feature_id)?;
+    validate_rating(rating)?;
+    let feature_tree = open_feature_tree(&db, &feature_id)?;
+    if !db.open_tree("track_details")?.contains_key(&track_id)? {
+        return Err(DataInputError::TrackNotFound(track_id));
+    }
+    let rating = learning::Rating::now(rating);
+    feature_tree.insert(track_id, &rating.encode())?;
+    crate::stats::record_rated_at(&db, &feature_id, rating.rated_at)?;
+    invalidate_model(&models, &feature_id).await;
+    Ok("ok")
+}
+
+/// Marks the track as irrelevant to the feature, so that it isn't offered for labeling again
+/// while staying out of the training data.
+#[instrument(skip(db, models))]
+async fn skip_track_for_feature(
+    Extension(State { db, models, .. }): Extension<State>,
+    Path((feature_id, track_id)): Path<(String, String)>,
+) -> Result<&'static str> {
+    validate_feature_id(&feature_id)?;
+    let feature_tree = open_feature_tree(&db, &feature_id)?;
+    if !db.open_tree("track_details")?.contains_key(&track_id)? {
+        return Err(DataInputError::TrackNotFound(track_id));
+    }
+    let rating = learning::Rating::now(learning::SKIP_RATING);
+    feature_tree.insert(track_id, &rating.encode())?;
+    crate::stats::record_rated_at(&db, &feature_id, rating.rated_at)?;
+    invalidate_model(&models, &feature_id).await;
+    Ok("ok")
+}
+
+/// Applies several ratings atomically, rejecting all of them if any one is invalid.
+#[instrument(skip(db, models, ratings))]
+async fn rate_feature_for_tracks(
+    Extension(State { db, models, .. }): Extension<State>,
+    Path(feature_id): Path<String>,
+    Json(ratings): Json<Vec<RatingDump>>,
+) -> Result<Json<usize>> {
+    validate_feature_id(&feature_id)?;
+    let feature_tree = open_feature_tree(&db, &feature_id)?;
+    let details_tree = db.open_tree("track_details")?;
+    let mut batch = sled::Batch::default();
+    let mut last_rated_at = 0;
+    for RatingDump {
+        track_id, rating, ..
+    } in ratings.iter()
+    {
+        validate_rating(*rating)?;
+        if !details_tree.contains_key(track_id)? {
+            return Err(DataInputError::TrackNotFound(track_id.clone()));
+        }
+        let rating = learning::Rating::now(*rating);
+        last_rated_at = last_rated_at.max(rating.rated_at);
+        batch.insert(track_id.as_bytes(), &rating.encode());
+    }
+    feature_tree.apply_batch(batch)?;
+    if !ratings.is_empty() {
+        crate::stats::record_rated_at(&db, &feature_id, last_rated_at)?;
+    }
+    invalidate_model(&models, &feature_id).await;
+    Ok(Json(ratings.len()))
+}
+
+fn validate_rating(rating: u8) -> Result<()> {
+    if rating > MAX_RATING {
+        return Err(DataInputError::InvalidInput(format!(
+            "rating {} is above the maximum of {}",
+            rating, MAX_RATING
+        )));
+    }
+    Ok(())
+}
+
+/// Details of every requested track known to the database, keyed by track id.
+#[instrument(skip(db, track_ids))]
+async fn tracks_details(
+    Extension(State { db, .. }): Extension<State>,
+    Json(track_ids): Json<Vec<String>>,
+) -> Result<Json<BTreeMap<String, SimplifiedTrack>>> {
+    let details_tree = db.open_tree("track_details")?;
+    let codec = Codec::of(&db)?;
+    let mut details = BTreeMap::new();
+    for track_id in track_ids {
+        if let Some(details_bytes) = details_tree.get(&track_id)? {
+            details.insert(
+                track_id,
+                serde_json::from_slice(&codec.decode(&details_bytes)?)?,
+            );
+        }
+    }
+    Ok(Json(details))
+}
+
+/// Fetches a single track's details and audio features from Spotify into the database.
+#[instrument(skip(db, client, governor))]
+async fn fetch_track(
+    Extension(State {
+        db,
+        client,
+        governor,
+        ..
+    }): Extension<State>,
+    Path(track_id): Path<String>,
+) -> Result<Json<SimplifiedTrack>> {
+    let id = TrackId::from_id(&track_id)
+        .map_err(|_| DataInputError::InvalidInput(format!("invalid track id {}", track_id)))?;
+    let track = {
+        let _permit = governor.acquire().await;
+        client.track(&id).await
+    };
+    let track = match track {
+        Ok(track) => track,
+        Err(error) if is_not_found(&error) => return Err(DataInputError::TrackNotFound(track_id)),
+        Err(error) => return Err(error.into()),
+    };
+    let extras = learning::TrackExtras::new(track.popularity, track.album.release_date.as_deref());
+    let images = serde_json::to_vec(&track.album.images)?;
+    let details = crate::simplify_track(track).ok_or_else(|| {
+        DataInputError::InvalidInput(format!("track {} is a local file", track_id))
+    })?;
+    // a track without analysis gets the same null marker population stores
+    let features = {
+        let _permit = governor.acquire().await;
+        client.track_features(&id).await
+    };
+    let features = match features {
+        Ok(features) => serde_json::to_vec(&features)?,
+        Err(error) if is_not_found(&error) => serde_json::to_vec(&serde_json::Value::Null)?,
+        Err(error) => return Err(error.into()),
+    };
+    let codec = Codec::of(&db)?;
+    db.open_tree("track_details")?
+        .insert(&track_id, codec.encode(&serde_json::to_vec(&details)?)?)?;
+    db.open_tree("track_extras")?
+        .insert(&track_id, serde_json::to_vec(&extras)?)?;
+    db.open_tree("track_features")?
+        .insert(&track_id, codec.encode(&features)?)?;
+    crate::stats::forget_tracks_with_features(&db)?;
+    db.open_tree("album_art")?.insert(&track_id, images)?;
+    Ok(Json(details))
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct TrackFeaturesQuery {
+    /// Feature whose stored model should score the track.
+    feature: Option<String>,
+}
+
+#[derive(Debug, serde::Serialize)]
+struct TrackFeatures {
+    features: BTreeMap<String, f32>,
+    /// Positive class probability from the stored model of the requested feature.
+    ///
+    /// Tree models have no per-column contributions to report, so the importance endpoint
+    /// is the place to look for why a feature's model weighs a column.
+    probability: Option<f32>,
+}
+
+/// The dataset columns of a track, as the models see them.
+#[instrument(skip(db))]
+async fn track_features(
+    Extension(State { db, .. }): Extension<State>,
+    Path(track_id): Path<String>,
+    Query(TrackFeaturesQuery { feature }): Query<TrackFeaturesQuery>,
+) -> Result<Json<TrackFeatures>> {
+    let columns = learning::track_columns(&db, &track_id)?
+        .ok_or_else(|| DataInputError::TrackNotFound(track_id.clone()))?;
+    let probability = match feature {
+        Some(feature_id) => {
+            validate_feature_id(&feature_id)?;
+            open_feature_tree(&db, &feature_id)?;
+            learning::stored_model(&db, &feature_id)?.map(|model| {
+                let record = Array2::from_shape_vec(
+                    (1, columns.len()),
+                    columns.iter().map(|(_, value)| *value).collect(),
+                )
+                .expect("record shape matches its length");
+                model.predict_probability(&record)[0]
+            })
+        }
+        None => None,
+    };
+    Ok(Json(TrackFeatures {
+        features: columns.into_iter().collect(),
+        probability,
+    }))
+}
+
+/// The audio features stored for a track exactly as Spotify returned them, for diagnosing models.
+/// Tracks Spotify has no features for are stored as a null, reported as `{"null": true}`.
+#[instrument(skip(db))]
+async fn raw_track_features(
+    Extension(State { db, .. }): Extension<State>,
+    Path(track_id): Path<String>,
+) -> Result<Json<serde_json::Value>> {
+    let features_bytes = db
+        .open_tree("track_features")?
+        .get(&track_id)?
+        .ok_or_else(|| DataInputError::TrackNotFound(track_id.clone()))?;
+    let features: serde_json::Value =
+        serde_json::from_slice(&Codec::of(&db)?.decode(&features_bytes)?)?;
+    if features.is_null() {
+        return Ok(Json(serde_json::json!({ "null": true })));
+    }
+    Ok(Json(features))
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct LimitQuery {
+    limit: Option<usize>,
+}
+
+#[derive(Debug, serde::Serialize)]
+struct MissingFeatures {
+    /// Number of tracks without features, including those beyond the limit.
+    total: usize,
+    tracks: Vec<MissingFeaturesTrack>,
+}
+
+#[derive(Debug, serde::Serialize)]
+struct MissingFeaturesTrack {
+    id: String,
+    name: String,
+}
+
+/// Stored tracks the models cannot see because their audio features are absent or null.
+#[instrument(skip(db))]
+async fn missing_features(
+    Extension(State { db, .. }): Extension<State>,
+    Query(LimitQuery { limit }): Query<LimitQuery>,
+) -> Result<Json<MissingFeatures>> {
+    let details_tree = db.open_tree("track_details")?;
+    let features_tree = db.open_tree("track_features")?;
+    let codec = Codec::of(&db)?;
+    let null_json = serde_json::to_vec(&serde_json::Value::Null)?;
+    let mut total = 0;
+    let mut tracks = vec![];
+    for it in details_tree.iter() {
+        let (id, details_bytes) = it?;
+        match features_tree.get(&id)? {
+            Some(val) if *codec.decode(&val)? != *null_json => continue,
+            _ => total += 1,
+        }
+        if limit.map_or(true, |limit| tracks.len() < limit) {
+            let details: SimplifiedTrack = serde_json::from_slice(&codec.decode(&details_bytes)?)?;
+            tracks.push(MissingFeaturesTrack {
+                id: String::from_utf8_lossy(&id).to_string(),
+                name: details.name,
+            });
+        }
+    }
+    Ok(Json(MissingFeatures { total, tracks }))
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct HistogramQuery {
+    bins: Option<usize>,
+}
+
+#[derive(Debug, serde::Serialize)]
+struct Histogram {
+    min: f64,
+    max: f64,
+    counts: Vec<usize>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct ClustersQuery {
+    k: Option<usize>,
+}
+
+/// Natural groupings of the library by audio features, keyed by cluster number.
+#[instrument(skip(db))]
+async fn clusters(
+    Extension(State { db, .. }): Extension<State>,
+    Query(ClustersQuery { k }): Query<ClustersQuery>,
+) -> Result<Json<BTreeMap<usize, Vec<String>>>> {
+    let k = k.unwrap_or(8);
+    if k == 0 {
+        return Err(DataInputError::InvalidInput(
+            "k must be positive".to_string(),
+        ));
+    }
+    Ok(Json(
+        learning::cluster_library(db, k)
+            .await?
+            .into_iter()
+            .enumerate()
+            .collect(),
+    ))
+}
+
+/// Distribution of a single audio feature across every track with stored features.
+#[instrument(skip(db))]
+async fn histogram(
+    Extension(State { db, .. }): Extension<State>,
+    Path(feature): Path<String>,
+    Query(HistogramQuery { bins }): Query<HistogramQuery>,
+) -> Result<Json<Histogram>> {
+    if !learning::FEATURE_NAMES.contains(&feature.as_str()) {
+        return Err(DataInputError::InvalidInput(format!(
+            "unknown audio feature {}",
+            feature
+        )));
+    }
+    let bins = bins.unwrap_or(20);
+    if bins == 0 {
+        return Err(DataInputError::InvalidInput(
+            "bins must be positive".to_string(),
+        ));
+    }
+    let codec = Codec::of(&db)?;
+    let mut values = vec![];
+    for it in db.open_tree("track_features")?.iter() {
+        let (_, features_bytes) = it?;
+        let features: serde_json::Value = serde_json::from_slice(&codec.decode(&features_bytes)?)?;
+        if let Some(value) = features.get(&feature).and_then(serde_json::Value::as_f64) {
+            values.push(value);
+        }
+    }
+    let min = values.iter().copied().fold(f64::INFINITY, f64::min);
+    let max = values.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+    let width = (max - min) / bins as f64;
+    let mut counts = vec![0; bins];
+    for value in values {
+        let bin = if width > 0.0 {
+            (((value - min) / width) as usize).min(bins - 1)
+        } else {
+            0
+        };
+        counts[bin] += 1;
+    }
+    Ok(Json(Histogram { min, max, counts }))
+}
+
+#[instrument(skip(db))]
+async fn health(
+    Extension(State { db, .. }): Extension<State>,
+) -> (StatusCode, Json<serde_json::Value>) {
+    let db_trees = db.tree_names().len();
+    match db.first() {
+        Ok(_) => (
+            StatusCode::OK,
+            Json(serde_json::json!({ "status": "ok", "db_trees": db_trees })),
+        ),
+        Err(error) => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(serde_json::json!({ "status": "unavailable", "error": error.to_string() })),
+        ),
+    }
+}
+
+#[derive(Debug, serde::Serialize)]
+struct Summary {
+    tracks: usize,
+    tracks_with_features: usize,
+    features: Vec<FeatureInfo>,
+}
+
+/// Library and labeling totals for a landing page.
+#[instrument(skip(db))]
+async fn summary(Extension(State { db, .. }): Extension<State>) -> Result<Json<Summary>> {
+    let mut features = vec![];
+    for name in db.tree_names() {
+        let name = String::from_utf8_lossy(&name).to_string();
+        if let Some(feature) = name.strip_prefix("input/") {
+            features.push(FeatureInfo::of(&db, feature)?);
+        }
+    }
+    Ok(Json(Summary {
+        tracks: db.open_tree("track_details")?.len(),
+        tracks_with_features: crate::stats::tracks_with_features(&db)?,
+        features,
+    }))
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct ThresholdQuery {
+    threshold: Option<f32>,
+}
+
+#[derive(Debug, Clone, Copy, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Combinator {
+    And,
+    Or,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct FeatureCondition {
+    feature: String,
+    /// Overrides the feature's stored threshold.
+    threshold: Option<f32>,
+    /// Asks for tracks the model rejects instead.
+    #[serde(default)]
+    negate: bool,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct FeatureQuery {
+    combinator: Combinator,
+    conditions: Vec<FeatureCondition>,
+    /// Playlist to replace with the result, if any.
+    playlist_id: Option<String>,
+}
+
+/// Tracks satisfying a combination of features, e.g. danceable and not sad, best matching
+/// first, optionally written to a playlist.
+#[instrument(skip(db, client, governor, models))]
+async fn query_features(
+    Extension(State {
+        db,
+        client,
+        governor,
+        models,
+        ..
+    }): Extension<State>,
+    Json(query): Json<FeatureQuery>,
+) -> Result<Json<Vec<String>>> {
+    if query.conditions.is_empty() {
+        return Err(DataInputError::InvalidInput(
+            "query needs at least one condition".to_string(),
+        ));
+    }
+    let playlist = query
+        .playlist_id
+        .as_deref()
+        .map(PlaylistId::from_id)
+        .transpose()
+        .map_err(|_| DataInputError::InvalidInput("invalid playlist id".to_string()))?;
+    // every track's probability of matching each condition, in the order of the conditions
+    let mut matches: HashMap<String, Vec<(f32, bool)>> = HashMap::new();
+    for condition in &query.conditions {
+        open_feature_tree(&db, &condition.feature)?;
+        let threshold = match condition.threshold {
+            Some(threshold) => threshold,
+            None => learning::stored_threshold(&db, &condition.feature)?,
+        };
+        let model = match cached_model(&db, &models, &condition.feature).await {
+            Ok(model) => model,
+            Err(error) => {
+                warn!(feature = %condition.feature, %error, "cannot fit model for query");
+                return Err(DataInputError::ModelNotFound(condition.feature.clone()));
+            }
+        };
+        for (track_id, probability) in learning::score_tracks_with(db.clone(), &model).await? {
+            let (probability, matched) = if condition.negate {
+                (1. - probability, probability < threshold)
+            } else {
+                (probability, probability >= threshold)
+            };
+            matches
+                .entry(track_id)
+                .or_default()
+                .push((probability, matched));
+        }
+    }
+    let mut selected = matches
+        .into_iter()
+        .filter(|(_, results)| match query.combinator {
+            Combinator::And => results.iter().all(|(_, matched)| *matched),
+            Combinator::Or => results.iter().any(|(_, matched)| *matched),
+        })
+        .map(|(track_id, results)| {
+            let score = results
+                .iter()
+                .map(|(probability, _)| probability)
+                .sum::<f32>()
+                / results.len() as f32;
+            (track_id, score)
+        })
+        .collect::<Vec<_>>();
+    selected.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+    let track_ids: Vec<String> = selected.into_iter().map(|(track_id, _)| track_id).collect();
+    if let Some(playlist) = playlist {
+        let tracks = track_ids
+            .iter()
+            .map(|track_id| TrackId::from_id(track_id))
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        crate::guard_track_count(&playlist, tracks.len())
+            .map_err(|error| DataInputError::InvalidInput(error.to_string()))?;
+        crate::write_playlist(
+            &Governed::new(client, governor),
+            &playlist,
+            tracks.iter().map(|track| track as &dyn PlayableId),
+            crate::WriteMode::Replace,
+        )
+        .await?;
+    }
+    Ok(Json(track_ids))
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct SetThreshold {
+    threshold: f32,
+}
+
+/// Sets the probability from which the feature's playlists include a track, used whenever a
+/// request doesn't pass its own `threshold`.
+#[instrument(skip(db))]
+async fn set_feature_threshold(
+    Extension(State { db, .. }): Extension<State>,
+    Path(feature_id): Path<String>,
+    Json(SetThreshold { threshold }): Json<SetThreshold>,
+) -> Result<&'static str> {
+    open_feature_tree(&db, &feature_id)?;
+    if !(0.0..=1.0).contains(&threshold) {
+        return Err(DataInputError::InvalidInput(format!(
+            "threshold must be between 0 and 1, got {}",
+            threshold
+        )));
+    }
+    learning::store_threshold(&db, &feature_id, threshold)?;
+    Ok("ok")
+}
+
+#[derive(Debug, serde::Serialize)]
+struct ScoredTrack {
+    #[serde(flatten)]
+    details: SimplifiedTrack,
+    probability: f32,
+}
+
+/// Unrated tracks the feature's model is least sure about, whose ratings teach it the most.
+#[instrument(skip(db, models))]
+async fn uncertain_for_feature(
+    Extension(State { db, models, .. }): Extension<State>,
+    Path(feature_id): Path<String>,
+    Query(LimitQuery { limit }): Query<LimitQuery>,
+) -> Result<Json<Vec<ScoredTrack>>> {
+    let feature_tree = open_feature_tree(&db, &feature_id)?;
+    let mut positive = 0;
+    let mut negative = 0;
+    for it in feature_tree.iter() {
+        let (_, rating) = it?;
+        match learning::Rating::decode(&rating)?.rating {
+            learning::SKIP_RATING => {}
+            0 => negative += 1,
+            _ => positive += 1,
+        }
+    }
+    if positive == 0 || negative == 0 {
+        return Err(DataInputError::InvalidInput(format!(
+            "cannot train a model for feature {} yet, it needs at least one positive and one \
+             negative rating, has {} positive / {} negative",
+            feature_id, positive, negative
+        )));
+    }
+    let model = cached_model(&db, &models, &feature_id).await?;
+    let details_tree = db.open_tree("track_details")?;
+    let codec = Codec::of(&db)?;
+    let mut scored = vec![];
+    for (track_id, probability) in learning::score_tracks_with(db.clone(), &model).await? {
+        if feature_tree.contains_key(&track_id)? {
+            continue;
+        }
+        scored.push((track_id, probability));
+    }
+    scored.sort_by(|(_, a), (_, b)| {
+        (a - learning::DEFAULT_THRESHOLD)
+            .abs()
+            .total_cmp(&(b - learning::DEFAULT_THRESHOLD).abs())
+    });
+    let mut tracks = vec![];
+    for (track_id, probability) in scored {
+        if tracks.len() == limit.unwrap_or(20) {
+            break;
+        }
+        if let Some(details_bytes) = details_tree.get(&track_id)? {
+            tracks.push(ScoredTrack {
+                details: serde_json::from_slice(&codec.decode(&details_bytes)?)?,
+                probability,
+            });
+        }
+    }
+    Ok(Json(tracks))
+}
+
+/// Tracks the feature's model accepts, as an M3U playlist of Spotify URIs in library order.
+///
+/// The tracks are scored and the playlist streamed a batch at a time, like [`backup`], so
+/// memory use doesn't grow with the library.
+#[instrument(skip(db, models))]
+async fn feature_playlist_m3u(
+    Extension(State { db, models, .. }): Extension<State>,
+    Path(feature_id): Path<String>,
+    Query(ThresholdQuery { threshold }): Query<ThresholdQuery>,
+) -> Result<(
+    Headers<Vec<(&'static str, &'static str)>>,
+    StreamBody<impl futures_util::Stream<Item = color_eyre::Result<String>>>,
+)> {
+    validate_feature_id(&feature_id)?;
+    open_feature_tree(&db, &feature_id)?;
+    let threshold = match threshold {
+        Some(threshold) => threshold,
+        None => learning::stored_threshold(&db, &feature_id)?,
+    };
+    let model = cached_model(&db, &models, &feature_id).await?;
+    let accepted = learning::accepted_tracks(&db, model, threshold)?;
+    Ok((
+        Headers(vec![("content-type", "audio/x-mpegurl")]),
+        StreamBody::new(futures_util::stream::iter(m3u_chunks(&db, accepted)?)),
+    ))
+}
+
+/// The M3U playlist of the tracks, one entry per chunk, with the name and duration of every
+/// track whose details are stored.
+fn m3u_chunks(
+    db: &Db,
+    track_ids: impl Iterator<Item = color_eyre::Result<String>> + Send + 'static,
+) -> Result<impl Iterator<Item = color_eyre::Result<String>> + Send + 'static> {
+    let details_tree = db.open_tree("track_details")?;
+    let codec = Codec::of(db)?;
+    let entries = track_ids.map(move |track_id| -> color_eyre::Result<String> {
+        let track_id = track_id?;
+        let mut entry = String::new();
+        if let Some(details_bytes) = details_tree.get(&track_id)? {
+            let details: SimplifiedTrack = serde_json::from_slice(&codec.decode(&details_bytes)?)?;
+            let artists = details
+                .artists
+                .iter()
+                .map(|artist| artist.name.as_str())
+                .collect::<Vec<_>>()
+                .join(", ");
+            entry.push_str(&format!(
+                "#EXTINF:{},{} - {}\n",
+                details.duration.as_secs(),
+                artists,
+                details.name
+            ));
+        }
+        entry.push_str(&format!("spotify:track:{}\n", track_id));
+        Ok(entry)
+    });
+    Ok(iter::once(Ok("#EXTM3U\n".to_string())).chain(entries))
+}
+
+/// Removes every rating of a feature while keeping the feature itself.
+#[instrument(skip(db, models))]
+async fn reset_feature(
+    Extension(State { db, models, .. }): Extension<State>,
+    Path(feature_id): Path<String>,
+) -> Result<Json<usize>> {
+    validate_feature_id(&feature_id)?;
+    let feature_tree = open_feature_tree(&db, &feature_id)?;
+    let removed = feature_tree.len();
+    feature_tree.clear()?;
+    db.drop_tree(format!("cursor/{}", feature_id))?;
+    crate::stats::forget_rated_at(&db, &feature_id)?;
+    invalidate_model(&models, &feature_id).await;
+    Ok(Json(removed))
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct RenameFeature {
+    name: String,
+}
+
+#[instrument(skip(db, models))]
+async fn rename_feature(
+    Extension(State { db, models, .. }): Extension<State>,
+    Path(feature_id): Path<String>,
+    Json(RenameFeature { name }): Json<RenameFeature>,
+) -> Result<&'static str> {
+    validate_feature_id(&feature_id)?;
+    validate_new_feature_id(&name)?;
+    let old_tree = open_feature_tree(&db, &feature_id)?;
+    if feature_exists(&db, &name) {
+        return Err(DataInputError::InvalidInput(format!(
+            "feature {} already exists",
+            name
+        )));
+    }
+    // the ratings move together with the threshold, model and metadata in `models/{feature}`
+    let trees = [
+        old_tree,
+        db.open_tree(format!("input/{}", name))?,
+        db.open_tree(format!("models/{}", feature_id))?,
+        db.open_tree(format!("models/{}", name))?,
+    ];
+    let entries = [&trees[0], &trees[2]]
+        .iter()
+        .map(|tree| tree.iter().collect::<sled::Result<Vec<_>>>())
+        .collect::<sled::Result<Vec<_>>>()?;
+    (&trees[..])
+        .transaction(|trees| {
+            for (i, entries) in entries.iter().enumerate() {
+                let (from, to) = (&trees[2 * i], &trees[2 * i + 1]);
+                for (key, value) in entries {
+                    to.insert(key, value)?;
+                    from.remove(key)?;
+                }
+            }
+            Ok(())
+        })
+        .map_err(|error: TransactionError| {
+            eyre!("renaming feature {} failed: {}", feature_id, error)
+        })?;
+    // the emptied trees of the old name are only dropped once everything moved
+    db.drop_tree(format!("input/{}", feature_id))?;
+    db.drop_tree(format!("models/{}", feature_id))?;
+    db.drop_tree(format!("cursor/{}", feature_id))?;
+    crate::stats::forget_rated_at(&db, &feature_id)?;
+    crate::stats::forget_rated_at(&db, &name)?;
+    invalidate_model(&models, &feature_id).await;
+    invalidate_model(&models, &name).await;
+    Ok("ok")
+}
+
+/// The [`learning::tree_importance`] of the feature's model.
+#[instrument(skip(db, models))]
+async fn feature_importance(
+    Extension(State { db, models, .. }): Extension<State>,
+    Path(feature_id): Path<String>,
+) -> Result<Json<Vec<(String, f32)>>> {
+    open_feature_tree(&db, &feature_id)?;
+    let model = cached_model(&db, &models, &feature_id).await?;
+    Ok(Json(learning::tree_importance(&model)))
+}
+
+#[instrument(skip(db))]
+async fn evaluate_feature(
+    Extension(State { db, .. }): Extension<State>,
+    Path(feature_id): Path<String>,
+    Query(ThresholdQuery { threshold }): Query<ThresholdQuery>,
+) -> Result<Json<learning::ModelMetrics>> {
+    open_feature_tree(&db, &feature_id)?;
+    let threshold = match threshold {
+        Some(threshold) => threshold,
+        None => learning::stored_threshold(&db, &feature_id)?,
+    };
+    let dataset = learning::feature_dataset_for_fitting(db, &feature_id).await?;
+    Ok(Json(learning::evaluate(
+        &dataset,
+        learning::Algorithm::from_env()?,
+        threshold,
+    )?))
+}
+
+/// When the feature's persisted model was trained and on what, to tell whether it is stale.
+#[instrument(skip(db))]
+async fn feature_model_metadata(
+    Extension(State { db, .. }): Extension<State>,
+    Path(feature_id): Path<String>,
+) -> Result<Json<learning::ModelMetadata>> {
+    open_feature_tree(&db, &feature_id)?;
+    learning::stored_model_metadata(&db, &feature_id)?
+        .map(Json)
+        .ok_or(DataInputError::ModelNotFound(feature_id))
+}
+
+/// Unrated tracks closest to the feature's positive examples, without training a model.
+#[instrument(skip(db))]
+async fn similar_to_feature(
+    Extension(State { db, .. }): Extension<State>,
+    Path(feature_id): Path<String>,
+    Query(LimitQuery { limit }): Query<LimitQuery>,
+) -> Result<Json<Vec<(String, f32)>>> {
+    open_feature_tree(&db, &feature_id)?;
+    let mut similar = learning::similar_tracks(db, &feature_id).await?;
+    if let Some(limit) = limit {
+        similar.truncate(limit);
+    }
+    Ok(Json(similar))
+}
+
+/// Cross-validated ROC curve of the feature's model, for choosing a playlist threshold.
+#[instrument(skip(db))]
+async fn feature_roc(
+    Extension(State { db, .. }): Extension<State>,
+    Path(feature_id): Path<String>,
+) -> Result<Json<learning::RocCurve>> {
+    open_feature_tree(&db, &feature_id)?;
+    let dataset = learning::feature_dataset_for_fitting(db, &feature_id).await?;
+    Ok(Json(learning::roc_curve(
+        &dataset,
+        learning::Algorithm::from_env()?,
+    )?))
+}
+
+/// Document of a feature's ratings, written by [`dump_feature`] and read by [`restore_feature`].
+#[derive(Debug, serde::Deserialize)]
+struct FeatureDump {
+    feature: String,
+    ratings: Vec<RatingDump>,
+    track_details: BTreeMap<String, serde_json::Value>,
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+struct RatingDump {
+    track_id: String,
+    rating: u8,
+    /// When the rating was made, 0 if unknown; ignored when rating through the API.
+    #[serde(default)]
+    rated_at: i64,
+}
+
+/// The feature's [`FeatureDump`], streamed like [`backup`] so that neither the ratings nor the
+/// track details are ever held in memory all at once.
+#[instrument(skip(db))]
+async fn dump_feature(
+    Extension(State { db, .. }): Extension<State>,
+    Path(feature_id): Path<String>,
+) -> Result<(
+    Headers<Vec<(&'static str, &'static str)>>,
+    StreamBody<impl futures_util::Stream<Item = color_eyre::Result<String>>>,
+)> {
+    let chunks = feature_dump_chunks(&db, &feature_id)?;
+    Ok((
+        Headers(vec![("content-type", "application/json")]),
+        StreamBody::new(futures_util::stream::iter(chunks)),
+    ))
+}
+
+/// The JSON of a [`FeatureDump`], one rating or track's details per chunk.
+///
+/// The feature's ratings are iterated twice, first for the ratings and then for the details of
+/// their tracks, which keeps the details ordered by track id as before.
+fn feature_dump_chunks(
+    db: &Db,
+    feature_id: &str,
+) -> Result<impl Iterator<Item = color_eyre::Result<String>> + Send + 'static> {
+    let feature_tree = open_feature_tree(db, feature_id)?;
+    let details_tree = db.open_tree("track_details")?;
+    let codec = Codec::of(db)?;
+    let ratings = feature_tree
+        .iter()
+        .enumerate()
+        .map(|(i, it)| -> color_eyre::Result<String> {
+            let (id, rating) = it?;
+            let learning::Rating { rating, rated_at } = learning::Rating::decode(&rating)?;
+            let separator = if i == 0 { "" } else { "," };
+            Ok(format!(
+                "{}{}",
+                separator,
+                serde_json::to_string(&RatingDump {
+                    track_id: String::from_utf8_lossy(&id).to_string(),
+                    rating,
+                    rated_at,
+                })?
+            ))
+        });
+    let mut first = true;
+    let track_details = feature_tree
+        .iter()
+        .map(move |it| -> color_eyre::Result<Option<String>> {
+            let (id, _) = it?;
+            let details = match details_tree.get(&id)? {
+                Some(details) => details,
+                None => return Ok(None),
+            };
+            let details: serde_json::Value = serde_json::from_slice(&codec.decode(&details)?)?;
+            let separator = if std::mem::replace(&mut first, false) {
+                ""
+            } else {
+                ","
+            };
+            Ok(Some(format!(
+                "{}{}:{}",
+                separator,
+                serde_json::to_string(&String::from_utf8_lossy(&id))?,
+                details
+            )))
+        })
+        .filter_map(color_eyre::Result::transpose);
+    Ok(iter::once(Ok(format!(
+        r#"{{"feature":{},"ratings":["#,
+        serde_json::to_string(feature_id)?
+    )))
+    .chain(ratings)
+    .chain(iter::once(Ok(r#"],"track_details":{"#.to_string())))
+    .chain(track_details)
+    .chain(iter::once(Ok("}}".to_string()))))
+}
+
+#[instrument(skip(db, models, dump))]
+async fn restore_feature(
+    Extension(State { db, models, .. }): Extension<State>,
+    Path(feature_id): Path<String>,
+    Json(dump): Json<FeatureDump>,
+) -> Result<Json<usize>> {
+    // restoring creates the feature when it's absent, which must not bypass the reserved names
+    if feature_exists(&db, &feature_id) {
+        validate_feature_id(&feature_id)?;
+    } else {
+        validate_new_feature_id(&feature_id)?;
+    }
+    if dump.feature != feature_id {
+        return Err(DataInputError::InvalidInput(format!(
+            "dump of feature {} cannot be restored as {}",
+            dump.feature, feature_id
+        )));
+    }
     let feature_tree = db.open_tree(format!("input/{}", feature_id))?;
-    feature_tree.insert(track_id, &[rating])?;
+    let details_tree = db.open_tree("track_details")?;
+    let codec = Codec::of(&db)?;
+    for (track_id, details) in dump.track_details {
+        if !details_tree.contains_key(&track_id)? {
+            details_tree.insert(track_id, codec.encode(&serde_json::to_vec(&details)?)?)?;
+        }
+    }
+    let mut ratings = sled::Batch::default();
+    for RatingDump {
+        track_id,
+        rating,
+        rated_at,
+    } in dump.ratings.iter()
+    {
+        let rating = learning::Rating {
+            rating: *rating,
+            rated_at: *rated_at,
+        };
+        ratings.insert(track_id.as_bytes(), &rating.encode());
+    }
+    feature_tree.apply_batch(ratings)?;
+    // restored ratings may be older than the ones already there, so the newest is looked up anew
+    crate::stats::forget_rated_at(&db, &feature_id)?;
+    invalidate_model(&models, &feature_id).await;
+    Ok(Json(dump.ratings.len()))
+}
+
+/// Every tree of the database as one JSON document, for moving it to another machine.
+///
+/// The document is streamed as the trees are read, so memory use doesn't grow with the library.
+#[instrument(skip(db))]
+async fn backup(
+    Extension(State { db, .. }): Extension<State>,
+) -> Result<(
+    Headers<Vec<(&'static str, &'static str)>>,
+    StreamBody<impl futures_util::Stream<Item = sled::Result<String>>>,
+)> {
+    let chunks = crate::backup::export_db(&db)?;
+    Ok((
+        Headers(vec![("content-type", "application/json")]),
+        StreamBody::new(futures_util::stream::iter(chunks)),
+    ))
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct RestoreQuery {
+    #[serde(default)]
+    confirm: bool,
+}
+
+/// Replaces the whole database with a backup. Requires `?confirm=true`, since everything
+/// stored so far is lost.
+#[instrument(skip(db, models, body))]
+async fn restore(
+    Extension(State { db, models, .. }): Extension<State>,
+    Query(RestoreQuery { confirm }): Query<RestoreQuery>,
+    body: axum::body::Bytes,
+) -> Result<Json<usize>> {
+    if !confirm {
+        return Err(DataInputError::InvalidInput(
+            "restoring replaces the whole database, pass confirm=true to proceed".to_string(),
+        ));
+    }
+    let restored = crate::backup::import_db(&db, &body[..])?;
+    invalidate_all_models(&models).await;
+    Ok(Json(restored))
+}
+
+#[derive(Debug, serde::Serialize)]
+struct PlaylistDiff {
+    added: Vec<String>,
+    removed: Vec<String>,
+    unchanged: Vec<String>,
+}
+
+/// Changes the next update would make to the rolling playlist, without making them.
+#[instrument(skip(db, client, governor))]
+async fn update_preview(
+    Extension(State {
+        db,
+        client,
+        governor,
+        ..
+    }): Extension<State>,
+) -> Result<Json<PlaylistDiff>> {
+    let spotify = Governed::new(client, governor);
+    let main_playlist =
+        crate::fetch_playlist_items(&spotify, &PlaylistId::from_id(crate::MAIN_PLAYLIST_ID)?)
+            .await?;
+    let settings = crate::RollingSettings::load()?;
+    let desired: Vec<String> = crate::rolling_selection(&main_playlist, &db, &settings)?
+        .into_iter()
+        .map(|track| track.id.id().to_string())
+        .collect();
+    let current: Vec<String> =
+        crate::fetch_playlist(&spotify, &PlaylistId::from_id(crate::ROLLING_PLAYLIST_ID)?)
+            .await?
+            .into_iter()
+            .map(|track| track.id.id().to_string())
+            .collect();
+    let (unchanged, added): (Vec<String>, Vec<String>) = desired
+        .into_iter()
+        .partition(|track_id| current.contains(track_id));
+    let removed = current
+        .into_iter()
+        .filter(|track_id| !unchanged.contains(track_id))
+        .collect();
+    Ok(Json(PlaylistDiff {
+        added,
+        removed,
+        unchanged,
+    }))
+}
+
+#[derive(Debug, serde::Serialize)]
+struct Me {
+    id: String,
+    display_name: Option<String>,
+    email: Option<String>,
+    product: Option<SubscriptionLevel>,
+    playback_allowed: bool,
+}
+
+#[instrument(skip(client, governor))]
+async fn me(
+    Extension(State {
+        client,
+        governor,
+        playback_allowed,
+        ..
+    }): Extension<State>,
+) -> Result<Json<Me>> {
+    let _permit = governor.acquire().await;
+    let user = client.current_user().await?;
+    Ok(Json(Me {
+        id: user.id.id().to_string(),
+        display_name: user.display_name,
+        email: user.email,
+        product: user.product,
+        playback_allowed,
+    }))
+}
+
+#[instrument(skip(client, governor))]
+async fn devices(
+    Extension(State {
+        client, governor, ..
+    }): Extension<State>,
+) -> Result<Json<Vec<Device>>> {
+    let _permit = governor.acquire().await;
+    Ok(Json(client.device().await?))
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct PlayRequest {
+    track_id: String,
+    device_id: Option<String>,
+}
+
+#[instrument(skip(client, governor))]
+async fn play(
+    Extension(State {
+        client,
+        governor,
+        playback_allowed,
+        ..
+    }): Extension<State>,
+    Json(PlayRequest {
+        track_id,
+        device_id,
+    }): Json<PlayRequest>,
+) -> Result<&'static str> {
+    require_playback(playback_allowed)?;
+    let track_id = TrackId::from_id(&track_id)
+        .map_err(|_| DataInputError::InvalidInput(format!("invalid track id {}", track_id)))?;
+    let _permit = governor.acquire().await;
+    client
+        .start_uris_playback(
+            [&track_id as &dyn PlayableId],
+            device_id.as_deref(),
+            None,
+            None,
+        )
+        .await
+        .map_err(playback_error)?;
     Ok("ok")
 }
 
+#[derive(Debug, serde::Deserialize)]
+struct DeviceQuery {
+    device_id: Option<String>,
+}
+
+#[instrument(skip(client, governor))]
+async fn pause_playback(
+    Extension(State {
+        client,
+        governor,
+        playback_allowed,
+        ..
+    }): Extension<State>,
+    Query(DeviceQuery { device_id }): Query<DeviceQuery>,
+) -> Result<&'static str> {
+    require_playback(playback_allowed)?;
+    let _permit = governor.acquire().await;
+    client
+        .pause_playback(device_id.as_deref())
+        .await
+        .map_err(playback_error)?;
+    Ok("ok")
+}
+
+#[instrument(skip(client, governor))]
+async fn resume_playback(
+    Extension(State {
+        client,
+        governor,
+        playback_allowed,
+        ..
+    }): Extension<State>,
+    Query(DeviceQuery { device_id }): Query<DeviceQuery>,
+) -> Result<&'static str> {
+    require_playback(playback_allowed)?;
+    let _permit = governor.acquire().await;
+    client
+        .resume_playback(device_id.as_deref(), None)
+        .await
+        .map_err(playback_error)?;
+    Ok("ok")
+}
+
+#[instrument(skip(client, governor))]
+async fn seek_playback(
+    Extension(State {
+        client,
+        governor,
+        playback_allowed,
+        ..
+    }): Extension<State>,
+    Path(position_ms): Path<u32>,
+    Query(DeviceQuery { device_id }): Query<DeviceQuery>,
+) -> Result<&'static str> {
+    require_playback(playback_allowed)?;
+    let _permit = governor.acquire().await;
+    client
+        .seek_track(position_ms, device_id.as_deref())
+        .await
+        .map_err(playback_error)?;
+    Ok("ok")
+}
+
+fn has_status(error: &rspotify::ClientError, status: StatusCode) -> bool {
+    use rspotify::{http::HttpError, ClientError};
+    match error {
+        ClientError::Http(http_error) => matches!(
+            http_error.as_ref(),
+            HttpError::StatusCode(response) if response.status() == status
+        ),
+        _ => false,
+    }
+}
+
+fn is_not_found(error: &rspotify::ClientError) -> bool {
+    classify(error) == ErrorKind::NotFound
+}
+
+/// Explains the Spotify Connect failure modes that a user can act upon.
+fn playback_error(error: rspotify::ClientError) -> DataInputError {
+    if is_not_found(&error) {
+        return DataInputError::NoActiveDevice;
+    }
+    // Connect only lets premium accounts control playback
+    if has_status(&error, StatusCode::FORBIDDEN) {
+        return DataInputError::PremiumRequired;
+    }
+    error.into()
+}
+
 #[instrument(skip(client))]
-async fn spotify_token(Extension((_, client, _)): Extension<State>) -> Result<String> {
+async fn spotify_token(Extension(State { client, .. }): Extension<State>) -> Result<String> {
     let token = client.get_token().lock().await.unwrap().clone().unwrap();
     Ok(token.access_token)
 }
 
-#[instrument(skip(shutdown_mechanism))]
-async fn shutdown(Extension((_, _, shutdown_mechanism)): Extension<State>) -> Result<&'static str> {
+/// Sends the browser through a new Spotify login, completed by `/api/callback` when the
+/// interface listens on the callback port.
+#[instrument(skip(client))]
+async fn reauthorize(Extension(State { client, .. }): Extension<State>) -> Result<Redirect> {
+    let login_url = client.get_authorize_url(false)?;
+    Ok(Redirect::to(login_url.parse()?))
+}
+
+#[instrument(skip(db, shutdown_mechanism))]
+async fn shutdown(
+    Extension(State {
+        db,
+        shutdown_mechanism,
+        ..
+    }): Extension<State>,
+) -> Result<&'static str> {
+    crate::flush_database(&db).await?;
     shutdown_mechanism
         .lock()
         .await
@@ -163,6 +1895,18 @@ async fn shutdown(Extension((_, _, shutdown_mechanism)): Extension<State>) -> Re
     Ok("ok")
 }
 
+/// Serves a frontend asset built into the binary, used when [`STATIC_DIR`] is absent.
+#[instrument]
+async fn static_asset(
+    Path(file): Path<String>,
+) -> Result<(Headers<Vec<(&'static str, &'static str)>>, &'static str)> {
+    let (_, content_type, embedded) = EMBEDDED_ASSETS
+        .iter()
+        .find(|(name, _, _)| *name == file)
+        .ok_or_else(|| DataInputError::AssetNotFound(file.clone()))?;
+    Ok((Headers(vec![("content-type", *content_type)]), *embedded))
+}
+
 #[instrument]
 async fn data_input_html() -> Result<Html<String>> {
     Ok(Html(
@@ -182,18 +1926,390 @@ async fn data_input_html() -> Result<Html<String>> {
 }
 
 #[derive(Debug)]
-struct StringableReport(color_eyre::Report);
+pub(crate) enum DataInputError {
+    NoMoreTracks,
+    FeatureNotFound(String),
+    TrackNotFound(String),
+    InvalidInput(String),
+    NoActiveDevice,
+    PremiumRequired,
+    AssetNotFound(String),
+    ModelNotFound(String),
+    ReauthorizationRequired,
+    Internal(color_eyre::Report),
+}
+
+impl DataInputError {
+    fn status(&self) -> StatusCode {
+        match self {
+            Self::NoMoreTracks
+            | Self::FeatureNotFound(_)
+            | Self::TrackNotFound(_)
+            | Self::NoActiveDevice
+            | Self::AssetNotFound(_)
+            | Self::ModelNotFound(_) => StatusCode::NOT_FOUND,
+            Self::InvalidInput(_) => StatusCode::BAD_REQUEST,
+            Self::PremiumRequired => StatusCode::FORBIDDEN,
+            Self::ReauthorizationRequired => StatusCode::UNAUTHORIZED,
+            Self::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// Stable identifier of the error kind for the frontend to match on.
+    fn code(&self) -> &'static str {
+        match self {
+            Self::NoMoreTracks => "no_more_tracks",
+            Self::FeatureNotFound(_) => "feature_not_found",
+            Self::TrackNotFound(_) => "track_not_found",
+            Self::InvalidInput(_) => "invalid_input",
+            Self::NoActiveDevice => "no_active_device",
+            Self::PremiumRequired => "premium_required",
+            Self::AssetNotFound(_) => "asset_not_found",
+            Self::ModelNotFound(_) => "model_not_found",
+            Self::ReauthorizationRequired => "reauthorization_required",
+            Self::Internal(_) => "internal",
+        }
+    }
+}
+
+impl std::fmt::Display for DataInputError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NoMoreTracks => write!(f, "no more tracks"),
+            Self::FeatureNotFound(feature_id) => write!(f, "feature {} not found", feature_id),
+            Self::TrackNotFound(track_id) => write!(f, "track {} not found", track_id),
+            Self::InvalidInput(message) => write!(f, "invalid input: {}", message),
+            Self::NoActiveDevice => write!(
+                f,
+                "no active Spotify device, start playback on one of your devices first"
+            ),
+            Self::PremiumRequired => {
+                write!(
+                    f,
+                    "controlling playback requires a Spotify Premium account and the streaming scope"
+                )
+            }
+            Self::AssetNotFound(file) => write!(f, "static asset {} not found", file),
+            Self::ModelNotFound(feature_id) => {
+                write!(f, "no model has been trained for feature {}", feature_id)
+            }
+            Self::ReauthorizationRequired => write!(
+                f,
+                "Spotify session expired, open /api/reauthorize to log in again"
+            ),
+            Self::Internal(report) => write!(f, "{}", report),
+        }
+    }
+}
 
-impl<T: Into<color_eyre::Report>> From<T> for StringableReport {
+impl<T: Into<color_eyre::Report>> From<T> for DataInputError {
     fn from(t: T) -> Self {
-        Self(t.into())
+        let report = t.into();
+        let kind = match report.downcast_ref::<rspotify::ClientError>() {
+            Some(error) => Some(classify(error)),
+            None => report
+                .downcast_ref::<ClassifiedError>()
+                .map(|error| error.kind),
+        };
+        match kind {
+            Some(ErrorKind::Unauthorized) => Self::ReauthorizationRequired,
+            _ => Self::Internal(report),
+        }
     }
 }
 
-impl IntoResponse for StringableReport {
-    type Body = <String as IntoResponse>::Body;
-    type BodyError = <String as IntoResponse>::BodyError;
+impl IntoResponse for DataInputError {
+    type Body = <Json<serde_json::Value> as IntoResponse>::Body;
+    type BodyError = <Json<serde_json::Value> as IntoResponse>::BodyError;
     fn into_response(self) -> axum::http::Response<Self::Body> {
-        self.0.to_string().into_response()
+        let status = self.status();
+        let mut response =
+            Json(serde_json::json!({ "error": self.to_string(), "code": self.code() }))
+                .into_response();
+        *response.status_mut() = status;
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::temporary_db;
+
+    fn test_state(db: Db) -> State {
+        State {
+            db,
+            client: Client::default(),
+            governor: Governor::from_env().unwrap(),
+            shutdown_mechanism: Arc::new(Mutex::new(None)),
+            rng: Arc::new(Mutex::new(StdRng::seed_from_u64(0))),
+            models: Arc::default(),
+            playback_allowed: false,
+        }
+    }
+
+    #[test]
+    fn feature_ids_reaching_other_trees_are_rejected() {
+        for feature_id in ["../track_details", "input/foo", "", "a b"] {
+            assert!(
+                matches!(
+                    validate_feature_id(feature_id),
+                    Err(DataInputError::InvalidInput(_))
+                ),
+                "{:?} accepted as a feature id",
+                feature_id
+            );
+        }
+        validate_feature_id("chill_evening-2").unwrap();
+    }
+
+    #[test]
+    fn tracks_without_market_list_are_available_everywhere() {
+        let markets = vec!["PL".to_string()];
+        let mut details = crate::source::mock::simplified_track("track1");
+        details.available_markets = None;
+        assert!(is_available(&details, &markets));
+        details.available_markets = Some(vec![]);
+        assert!(is_available(&details, &markets));
+        details.available_markets = Some(vec!["DE".to_string(), "PL".to_string()]);
+        assert!(is_available(&details, &markets));
+        details.available_markets = Some(vec!["DE".to_string()]);
+        assert!(!is_available(&details, &markets));
+    }
+
+    #[tokio::test]
+    async fn reserved_feature_names_are_rejected() {
+        let db = temporary_db();
+        for reserved in RESERVED_FEATURE_NAMES {
+            assert!(
+                matches!(
+                    validate_new_feature_id(reserved),
+                    Err(DataInputError::InvalidInput(_))
+                ),
+                "{} accepted as a new feature",
+                reserved
+            );
+            let restored = restore_feature(
+                Extension(test_state(db.clone())),
+                Path(reserved.to_string()),
+                Json(FeatureDump {
+                    feature: reserved.to_string(),
+                    ratings: vec![],
+                    track_details: BTreeMap::new(),
+                }),
+            )
+            .await;
+            assert!(
+                matches!(restored, Err(DataInputError::InvalidInput(_))),
+                "{} restored as a feature",
+                reserved
+            );
+            assert!(!feature_exists(&db, reserved));
+        }
+    }
+
+    #[tokio::test]
+    async fn rename_moves_ratings_and_threshold() {
+        let db = temporary_db();
+        db.open_tree("input/old")
+            .unwrap()
+            .insert("track1", &learning::Rating::now(1).encode())
+            .unwrap();
+        learning::store_threshold(&db, "old", 0.7).unwrap();
+        rename_feature(
+            Extension(test_state(db.clone())),
+            Path("old".to_string()),
+            Json(RenameFeature {
+                name: "new".to_string(),
+            }),
+        )
+        .await
+        .unwrap();
+        assert!(!feature_exists(&db, "old"));
+        assert!(db
+            .open_tree("input/new")
+            .unwrap()
+            .contains_key("track1")
+            .unwrap());
+        assert_eq!(learning::stored_threshold(&db, "new").unwrap(), 0.7);
+        assert_eq!(
+            learning::stored_threshold(&db, "old").unwrap(),
+            learning::DEFAULT_THRESHOLD
+        );
+    }
+
+    /// Tracks in the synthetic library of the streaming tests, far more than any chunk could
+    /// hold if the responses were built whole.
+    const LARGE_LIBRARY: usize = 20_000;
+    /// Longest chunk a streamed response may produce, enough for a single track's details.
+    const MAX_CHUNK_LEN: usize = 4096;
+
+    fn large_library(db: &Db) {
+        let details_tree = db.open_tree("track_details").unwrap();
+        let feature_tree = db.open_tree("input/comfy").unwrap();
+        for i in 0..LARGE_LIBRARY {
+            let track_id = format!("track{}", i);
+            let details = crate::source::mock::simplified_track(&track_id);
+            details_tree
+                .insert(&track_id, serde_json::to_vec(&details).unwrap())
+                .unwrap();
+            feature_tree
+                .insert(&track_id, &learning::Rating::now(1).encode())
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn feature_dump_is_streamed_in_small_chunks() {
+        let db = temporary_db();
+        large_library(&db);
+        let mut dump = String::new();
+        let mut chunks = 0;
+        for chunk in feature_dump_chunks(&db, "comfy").unwrap() {
+            let chunk = chunk.unwrap();
+            assert!(
+                chunk.len() <= MAX_CHUNK_LEN,
+                "chunk of {} bytes",
+                chunk.len()
+            );
+            dump.push_str(&chunk);
+            chunks += 1;
+        }
+        assert_eq!(chunks, 2 * LARGE_LIBRARY + 3);
+        let dump: FeatureDump = serde_json::from_str(&dump).unwrap();
+        assert_eq!(dump.feature, "comfy");
+        assert_eq!(dump.ratings.len(), LARGE_LIBRARY);
+        assert_eq!(dump.track_details.len(), LARGE_LIBRARY);
+    }
+
+    #[test]
+    fn m3u_playlist_is_streamed_in_small_chunks() {
+        let db = temporary_db();
+        large_library(&db);
+        let track_ids = (0..LARGE_LIBRARY).map(|i| Ok(format!("track{}", i)));
+        let mut chunks = m3u_chunks(&db, track_ids).unwrap();
+        assert_eq!(chunks.next().unwrap().unwrap(), "#EXTM3U\n");
+        let mut entries = 0;
+        for chunk in chunks {
+            let chunk = chunk.unwrap();
+            assert!(
+                chunk.len() <= MAX_CHUNK_LEN,
+                "chunk of {} bytes",
+                chunk.len()
+            );
+            assert!(chunk.starts_with("#EXTINF:"), "{}", chunk);
+            entries += 1;
+        }
+        assert_eq!(entries, LARGE_LIBRARY);
+    }
+
+    /// Runs the labeling of `runs` batches the way a fresh start with a fixed seed would.
+    async fn seeded_session(db: &Db, runs: usize) -> Vec<String> {
+        forget_cursors(db).unwrap();
+        let state = test_state(db.clone());
+        let mut offered = vec![];
+        for _ in 0..runs {
+            let tracks = untrained_tracks(
+                db,
+                &state.client,
+                &state.governor,
+                &state.rng,
+                "comfy",
+                &EligibilityFilter::default(),
+                3,
+            )
+            .await
+            .unwrap();
+            offered.extend(tracks.into_iter().map(|track| track.details.name));
+        }
+        offered
+    }
+
+    #[tokio::test]
+    async fn runs_with_the_same_seed_offer_the_same_tracks() {
+        let db = temporary_db();
+        db.open_tree("input/comfy").unwrap();
+        let details_tree = db.open_tree("track_details").unwrap();
+        let features_tree = db.open_tree("track_features").unwrap();
+        let album_art_tree = db.open_tree("album_art").unwrap();
+        for i in 0..50 {
+            let track_id = format!("track{}", i);
+            let details = crate::source::mock::simplified_track(&track_id);
+            details_tree
+                .insert(&track_id, serde_json::to_vec(&details).unwrap())
+                .unwrap();
+            let features = crate::source::mock::audio_features(&track_id);
+            features_tree
+                .insert(&track_id, serde_json::to_vec(&features).unwrap())
+                .unwrap();
+            album_art_tree.insert(&track_id, "[]").unwrap();
+        }
+        let first = seeded_session(&db, 4).await;
+        assert_eq!(
+            first.iter().collect::<std::collections::HashSet<_>>().len(),
+            12
+        );
+        assert_eq!(seeded_session(&db, 4).await, first);
+    }
+
+    #[tokio::test]
+    /// Five tracks with details and features, of which all but `track4` are rated for `comfy`.
+    fn rated_library(db: &Db) {
+        let details_tree = db.open_tree("track_details").unwrap();
+        let features_tree = db.open_tree("track_features").unwrap();
+        let feature_tree = db.open_tree("input/comfy").unwrap();
+        for i in 0..5 {
+            let track_id = format!("track{}", i);
+            let details = crate::source::mock::simplified_track(&track_id);
+            details_tree
+                .insert(&track_id, serde_json::to_vec(&details).unwrap())
+                .unwrap();
+            let features = crate::source::mock::audio_features(&track_id);
+            features_tree
+                .insert(&track_id, serde_json::to_vec(&features).unwrap())
+                .unwrap();
+            if i < 4 {
+                feature_tree
+                    .insert(&track_id, &learning::Rating::now((i % 2) as u8).encode())
+                    .unwrap();
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn rating_a_track_refits_the_cached_model() {
+        let db = temporary_db();
+        rated_library(&db);
+        let state = test_state(db.clone());
+        let fitted = cached_model(&db, &state.models, "comfy").await.unwrap();
+        let cached = cached_model(&db, &state.models, "comfy").await.unwrap();
+        assert!(Arc::ptr_eq(&fitted, &cached));
+        rate_feature_for_track(
+            Extension(state.clone()),
+            Path(("comfy".to_string(), "track4".to_string(), 1)),
+        )
+        .await
+        .unwrap();
+        let refitted = cached_model(&db, &state.models, "comfy").await.unwrap();
+        assert!(!Arc::ptr_eq(&fitted, &refitted));
+    }
+
+    #[tokio::test]
+    async fn query_matching_nothing_leaves_the_playlist_alone() {
+        let db = temporary_db();
+        rated_library(&db);
+        let query = serde_json::from_value(serde_json::json!({
+            "combinator": "and",
+            "conditions": [{ "feature": "comfy", "threshold": 1.1 }],
+            "playlist_id": "target",
+        }))
+        .unwrap();
+        // the client of the test state can't reach Spotify, so any write attempt fails otherwise
+        let result = query_features(Extension(test_state(db)), Json(query)).await;
+        assert!(
+            matches!(result, Err(DataInputError::InvalidInput(_))),
+            "{:?}",
+            result
+        );
     }
 }
@@ -0,0 +1,54 @@
+use std::{sync::Arc, time::Duration};
+
+use color_eyre::{eyre::WrapErr, Result};
+use tokio::{
+    sync::{Mutex, OwnedSemaphorePermit, Semaphore},
+    time::Instant,
+};
+
+/// Spotify requests allowed in flight at once, unless `DEFY_SPOTIFY_CONCURRENCY` says otherwise.
+const DEFAULT_CONCURRENCY: usize = 4;
+/// Minimum gap between the starts of two Spotify requests, unless `DEFY_SPOTIFY_INTERVAL_MS`
+/// says otherwise.
+const DEFAULT_INTERVAL_MS: u64 = 50;
+
+/// Limits the Spotify requests made by every part of the program together, so that populating,
+/// updating and the web interface can't jointly run into the rate limit.
+#[derive(Debug, Clone)]
+pub(crate) struct Governor {
+    permits: Arc<Semaphore>,
+    interval: Duration,
+    next_start: Arc<Mutex<Instant>>,
+}
+
+impl Governor {
+    pub(crate) fn from_env() -> Result<Self> {
+        let concurrency = std::env::var("DEFY_SPOTIFY_CONCURRENCY")
+            .map_or(Ok(DEFAULT_CONCURRENCY), |concurrency| concurrency.parse())
+            .wrap_err("cannot parse DEFY_SPOTIFY_CONCURRENCY")?;
+        let interval_ms = std::env::var("DEFY_SPOTIFY_INTERVAL_MS")
+            .map_or(Ok(DEFAULT_INTERVAL_MS), |interval| interval.parse())
+            .wrap_err("cannot parse DEFY_SPOTIFY_INTERVAL_MS")?;
+        Ok(Self {
+            permits: Arc::new(Semaphore::new(concurrency.max(1))),
+            interval: Duration::from_millis(interval_ms),
+            next_start: Arc::new(Mutex::new(Instant::now())),
+        })
+    }
+
+    /// Waits until another request may start. The request counts as in flight until the
+    /// returned permit is dropped.
+    pub(crate) async fn acquire(&self) -> OwnedSemaphorePermit {
+        let permit = self
+            .permits
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("governor semaphore is never closed");
+        // holding the lock while sleeping queues the waiting requests one interval apart
+        let mut next_start = self.next_start.lock().await;
+        tokio::time::sleep_until(*next_start).await;
+        *next_start = Instant::now() + self.interval;
+        permit
+    }
+}
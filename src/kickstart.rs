@@ -1,116 +1,204 @@
-use std::sync::Arc;
+use std::{path::PathBuf, sync::Arc, time::Duration};
 
 use axum::{
     extract::{Extension, Query},
     routing::get,
     AddExtensionLayer, Router,
 };
-use color_eyre::eyre::Context;
+use color_eyre::eyre::{eyre, Context};
 
 use rspotify::{
     clients::{BaseClient, OAuthClient},
     AuthCodeSpotify,
 };
 use tokio::sync::{oneshot, Mutex};
-use tracing::instrument;
+use tracing::{debug, instrument, warn};
+
+use crate::{
+    client_error::{classified, ErrorKind},
+    config::Config,
+    data_input::DataInputError,
+};
+
+/// How long to wait for the user to complete a login before giving up on the attempt.
+const DEFAULT_AUTH_TIMEOUT_SECS: u64 = 300;
+/// Number of timed out login attempts tolerated before failing.
+const AUTH_ATTEMPTS: usize = 3;
 
 #[instrument]
-pub(crate) async fn kickstart() -> color_eyre::Result<Client> {
-    if let Ok(client) = authed_client().await {
-        return Ok(client);
+pub(crate) async fn kickstart(profile: Option<&str>) -> color_eyre::Result<Client> {
+    // built once so that every clone handed out shares its HTTP connection pool and token
+    let client = base_client(profile)?;
+    if load_cached_token(&client).await.is_err() {
+        login(&client).await?;
+    } else if let Err(error) = classified(client.current_user().await) {
+        if error.kind != ErrorKind::Unauthorized {
+            return Err(error.into());
+        }
+        warn!(%error, "cached Spotify session is no longer valid, logging in again");
+        login(&client).await?;
     }
-    loop {
-        match authed_client().await {
-            Ok(client) => return Ok(client),
-            _ => {
-                let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
-                let txs = Arc::new(Mutex::new(Some(shutdown_tx)));
-                let app = Router::new()
-                    .route("/api/callback", get(auth_callback))
-                    .layer(AddExtensionLayer::new(txs));
-                let bound_server = axum::Server::bind(
-                    &"127.0.0.1:3000"
-                        .parse()
-                        .wrap_err("cannot parse bind address")?,
-                )
-                .serve(app.into_make_service());
+    Ok(client)
+}
 
-                let login_url = base_client().get_authorize_url(false)?;
-                webbrowser::open(&login_url)?;
+/// Runs the interactive login flow, storing the new token in `client` and the token cache.
+#[instrument(skip(client))]
+pub(crate) async fn login(client: &Client) -> color_eyre::Result<()> {
+    let auth_timeout = Duration::from_secs(
+        std::env::var("DEFY_AUTH_TIMEOUT_SECS")
+            .map_or(Ok(DEFAULT_AUTH_TIMEOUT_SECS), |secs| secs.parse())
+            .wrap_err("cannot parse DEFY_AUTH_TIMEOUT_SECS")?,
+    );
+    for attempt in 1..=AUTH_ATTEMPTS {
+        let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+        let txs = Arc::new(Mutex::new(Some(shutdown_tx)));
+        // the callback must complete the flow with the client that issued the state
+        let login_url = client.get_authorize_url(false)?;
+        let app = Router::new()
+            .route("/api/callback", get(auth_callback))
+            .layer(AddExtensionLayer::new((client.clone(), txs)));
+        let bound_server = axum::Server::bind(
+            &format!("127.0.0.1:{}", crate::port_from_env("DEFY_CALLBACK_PORT"))
+                .parse()
+                .wrap_err("cannot parse bind address")?,
+        )
+        .serve(app.into_make_service());
 
-                bound_server
-                    .with_graceful_shutdown(async move {
-                        shutdown_rx.await.unwrap();
-                    })
-                    .await?;
-            }
+        crate::open_in_browser(&login_url);
+
+        let served = tokio::time::timeout(
+            auth_timeout,
+            bound_server.with_graceful_shutdown(async move {
+                shutdown_rx.await.unwrap();
+            }),
+        )
+        .await;
+        match served {
+            Ok(result) => return Ok(result?),
+            Err(_) => warn!(attempt, ?auth_timeout, "authentication attempt timed out"),
         }
     }
+    Err(eyre!("authentication timed out"))
 }
 
 #[instrument]
-fn base_client() -> Client {
-    const REDIRECT_URL: &str = "http://localhost:3000/api/callback";
-    rspotify::AuthCodeSpotify::with_config(
-        rspotify::Credentials::from_env().expect("missing credentials in env"),
+fn base_client(profile: Option<&str>) -> color_eyre::Result<Client> {
+    let config = Config::load()?;
+    let redirect_url = config.redirect_uri().unwrap_or_else(|| {
+        format!(
+            "http://localhost:{}/api/callback",
+            crate::port_from_env("DEFY_CALLBACK_PORT")
+        )
+    });
+    debug!("constructing spotify client");
+    Ok(rspotify::AuthCodeSpotify::with_config(
+        config.credentials()?,
         rspotify::OAuth {
-            redirect_uri: REDIRECT_URL.to_string(),
-            scopes: rspotify::scopes!(
-                "user-library-read",
-                "playlist-read-private",
-                "playlist-modify-private",
-                "playlist-modify-public",
-                "streaming",
-                "user-read-email",
-                "user-read-private"
-            ),
+            redirect_uri: redirect_url,
+            scopes: config.scopes().unwrap_or_else(|| {
+                rspotify::scopes!(
+                    "user-library-read",
+                    "playlist-read-private",
+                    "playlist-modify-private",
+                    "playlist-modify-public",
+                    "streaming",
+                    "user-read-email",
+                    "user-read-private",
+                    "user-read-playback-state",
+                    "user-modify-playback-state"
+                )
+            }),
             ..Default::default()
         },
         rspotify::Config {
             token_cached: true,
             token_refreshing: true,
+            cache_path: crate::profiled_path(
+                &std::env::var_os("DEFY_TOKEN_CACHE")
+                    .map(PathBuf::from)
+                    .unwrap_or_else(|| rspotify::Config::default().cache_path),
+                profile,
+            ),
             ..Default::default()
         },
-    )
+    ))
 }
 
-#[instrument]
-async fn authed_client() -> Result<Client, String> {
-    let mut client = base_client();
+/// Loads the cached token into `client` in place, keeping its credentials and connection pool.
+#[instrument(skip(client))]
+async fn load_cached_token(client: &Client) -> Result<(), String> {
     match client.read_token_cache().await {
-        Ok(Some(token)) => Ok(Client::from_token(token)),
+        Ok(Some(token)) => {
+            *client.get_token().lock().await.unwrap() = Some(token);
+            Ok(())
+        }
         _ => Err("unauthenticated".to_string()),
     }
 }
 
 #[derive(serde::Deserialize)]
-struct AuthCallbackQuery {
+pub(crate) struct AuthCallbackQuery {
     code: String,
     state: String,
 }
 
-#[instrument]
-async fn auth_callback(
+/// The client completing the flow and, when a login server waits for it, its shutdown trigger.
+pub(crate) type CallbackState = (Client, Arc<Mutex<Option<oneshot::Sender<()>>>>);
+
+#[instrument(skip(client, txs))]
+pub(crate) async fn auth_callback(
     Query(AuthCallbackQuery { code, state }): Query<AuthCallbackQuery>,
-    Extension(txs): Extension<Arc<Mutex<Option<oneshot::Sender<()>>>>>,
-) -> &'static str {
-    let mut client = base_client();
-    client.oauth.state = state;
-    client
-        .request_token(&code)
-        .await
-        .expect("requesting token failed");
-    client
-        .write_token_cache()
-        .await
-        .expect("writing token cache failed");
-    txs.lock()
-        .await
-        .take()
-        .expect("auth race lost")
-        .send(())
-        .unwrap();
-    "ok"
+    Extension((client, txs)): Extension<CallbackState>,
+) -> Result<&'static str, DataInputError> {
+    verify_state(&client, &state)?;
+    // an expired or reused code fails here, which the long-running interface has to survive
+    client.request_token(&code).await?;
+    client.write_token_cache().await?;
+    if let Some(tx) = txs.lock().await.take() {
+        tx.send(()).unwrap();
+    }
+    Ok("ok")
+}
+
+/// Rejects a callback whose state isn't the one issued with the authorization URL, since it
+/// wasn't started by this client.
+fn verify_state(client: &Client, state: &str) -> Result<(), DataInputError> {
+    if state != client.oauth.state {
+        return Err(DataInputError::InvalidInput("state mismatch".to_string()));
+    }
+    Ok(())
 }
 
 pub type Client = AuthCodeSpotify;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_the_issued_state_is_accepted() {
+        let client = Client::default();
+        verify_state(&client, &client.oauth.state).unwrap();
+        assert!(matches!(
+            verify_state(&client, "forged"),
+            Err(DataInputError::InvalidInput(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn callback_with_a_forged_state_keeps_the_login_server_running() {
+        let (tx, mut rx) = oneshot::channel();
+        let txs = Arc::new(Mutex::new(Some(tx)));
+        let result = auth_callback(
+            Query(AuthCallbackQuery {
+                code: "code".to_string(),
+                state: "forged".to_string(),
+            }),
+            Extension((Client::default(), txs.clone())),
+        )
+        .await;
+        assert!(matches!(result, Err(DataInputError::InvalidInput(_))));
+        assert!(txs.lock().await.is_some());
+        assert!(rx.try_recv().is_err());
+    }
+}
@@ -1,8 +1,267 @@
-use color_eyre::Result;
-use linfa::Dataset;
-use ndarray::Array2;
+use color_eyre::{eyre::eyre, Result};
+use itertools::Itertools;
+use linfa::prelude::*;
+use linfa_clustering::KMeans;
+use linfa_trees::DecisionTree;
+use ndarray::{Array1, Array2, ArrayBase, Axis, Data, Ix2};
+use rand::Rng;
 use rspotify::model::AudioFeatures;
-use tracing::{info, instrument};
+use tracing::{info, instrument, warn};
+
+use crate::compression::Codec;
+
+/// Names of the audio features making up each dataset record, in column order.
+pub(crate) const FEATURE_NAMES: [&str; 11] = [
+    "acousticness",
+    "danceability",
+    "energy",
+    "instrumentalness",
+    "key",
+    "liveness",
+    "loudness",
+    "speechiness",
+    "tempo",
+    "time_signature",
+    "valence",
+];
+
+/// Names of the columns derived from the audio features, following [`FEATURE_NAMES`].
+const DERIVED_FEATURE_NAMES: [&str; 1] = ["key_unknown"];
+
+/// Names of the optional non-audio columns appended when `DEFY_EXTRA_FEATURES` is set.
+const EXTRA_FEATURE_NAMES: [&str; 2] = ["popularity", "release_year"];
+
+/// Popularity and release year of a track, kept in the `track_extras` tree.
+#[derive(Debug, Default, Clone, serde::Serialize, serde::Deserialize)]
+pub(crate) struct TrackExtras {
+    pub(crate) popularity: Option<u32>,
+    pub(crate) release_year: Option<i32>,
+}
+
+impl TrackExtras {
+    pub(crate) fn new(popularity: u32, release_date: Option<&str>) -> Self {
+        Self {
+            popularity: Some(popularity),
+            release_year: release_date.and_then(|date| date.get(..4)?.parse().ok()),
+        }
+    }
+}
+
+/// Whether datasets include popularity and release year next to the audio features.
+pub(crate) fn include_extras() -> bool {
+    std::env::var("DEFY_EXTRA_FEATURES").is_ok()
+}
+
+/// Names of all dataset columns, in order, honouring `DEFY_EXTRA_FEATURES`.
+pub(crate) fn feature_names() -> Vec<&'static str> {
+    let mut names = FEATURE_NAMES.to_vec();
+    names.extend_from_slice(&DERIVED_FEATURE_NAMES);
+    if include_extras() {
+        names.extend_from_slice(&EXTRA_FEATURE_NAMES);
+    }
+    names
+}
+
+/// Rating stored for a track marked irrelevant to a feature: it is never offered for labeling
+/// again, but doesn't count as an example either way.
+pub(crate) const SKIP_RATING: u8 = u8::MAX;
+
+/// A rating stored in an `input/{feature}` tree, together with when it was made.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Rating {
+    pub(crate) rating: u8,
+    /// Unix timestamp in seconds, 0 for ratings stored before timestamps were kept.
+    pub(crate) rated_at: i64,
+}
+
+impl Rating {
+    pub(crate) fn now(rating: u8) -> Self {
+        Self {
+            rating,
+            rated_at: chrono::Utc::now().timestamp(),
+        }
+    }
+
+    /// The rating byte followed by the big-endian timestamp.
+    pub(crate) fn encode(&self) -> [u8; 9] {
+        let mut bytes = [0; 9];
+        bytes[0] = self.rating;
+        bytes[1..].copy_from_slice(&self.rated_at.to_be_bytes());
+        bytes
+    }
+
+    /// Reads a rating written by [`Rating::encode`] or a bare rating byte from before.
+    pub(crate) fn decode(bytes: &[u8]) -> Result<Self> {
+        match bytes {
+            [rating] => Ok(Self {
+                rating: *rating,
+                rated_at: 0,
+            }),
+            [rating, rated_at @ ..] if rated_at.len() == 8 => Ok(Self {
+                rating: *rating,
+                rated_at: i64::from_be_bytes(rated_at.try_into()?),
+            }),
+            _ => Err(eyre!("malformed rating of {} bytes", bytes.len())),
+        }
+    }
+
+    pub(crate) fn is_skip(&self) -> bool {
+        self.rating == SKIP_RATING
+    }
+}
+
+/// Key Spotify reports when it detected none.
+const UNKNOWN_KEY: i32 = -1;
+/// Time signatures Spotify documents, as beats per bar; anything else is a failed detection.
+const VALID_TIME_SIGNATURES: std::ops::RangeInclusive<i32> = 3..=7;
+
+/// Builds the dataset record of a track, or `None` if some of its columns are unavailable.
+///
+/// Spotify's sentinel values are kept out of the numeric columns: an undetected key becomes
+/// key 0 with the `key_unknown` column set, and a track whose time signature is outside
+/// [`VALID_TIME_SIGNATURES`] (Spotify sends 0 when detection failed) is left out entirely.
+fn track_record(
+    id: &[u8],
+    features_bytes: &[u8],
+    extras_tree: Option<&sled::Tree>,
+) -> Result<Option<Vec<f32>>> {
+    let features_option: AudioFeatures = serde_json::from_slice(features_bytes)?;
+    let features_object = match features_option {
+        Some(features_object) => features_object,
+        None => return Ok(None),
+    };
+    if !VALID_TIME_SIGNATURES.contains(&features_object.time_signature) {
+        return Ok(None);
+    }
+    let key_unknown = features_object.key == UNKNOWN_KEY;
+    let mut record = vec![
+        features_object.acousticness,
+        features_object.danceability,
+        features_object.energy,
+        features_object.instrumentalness,
+        features_object.key.max(0) as f32,
+        features_object.liveness,
+        features_object.loudness,
+        features_object.speechiness,
+        features_object.tempo,
+        features_object.time_signature as f32,
+        features_object.valence,
+        if key_unknown { 1. } else { 0. },
+    ];
+    if let Some(extras_tree) = extras_tree {
+        let extras: TrackExtras = match extras_tree.get(id)? {
+            Some(extras_bytes) => serde_json::from_slice(&extras_bytes)?,
+            None => return Ok(None),
+        };
+        match (extras.popularity, extras.release_year) {
+            (Some(popularity), Some(release_year)) => {
+                record.extend_from_slice(&[popularity as f32, release_year as f32])
+            }
+            _ => return Ok(None),
+        }
+    }
+    Ok(Some(record))
+}
+
+/// Named dataset columns of a single track, or `None` if some of them are unavailable.
+pub(crate) fn track_columns(db: &sled::Db, track_id: &str) -> Result<Option<Vec<(String, f32)>>> {
+    let features_bytes = match db.open_tree("track_features")?.get(track_id)? {
+        Some(features_bytes) => features_bytes,
+        None => return Ok(None),
+    };
+    let record = track_record(
+        track_id.as_bytes(),
+        &Codec::of(db)?.decode(&features_bytes)?,
+        extras_tree(db)?.as_ref(),
+    )?;
+    Ok(record.map(|record| {
+        feature_names()
+            .into_iter()
+            .map(|name| name.to_string())
+            .zip(record)
+            .collect()
+    }))
+}
+
+/// A bound on one dataset column, written as e.g. `danceability >= 0.6` or `energy <= 0.3`.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct FeatureThreshold {
+    feature: String,
+    at_least: bool,
+    value: f32,
+}
+
+impl std::str::FromStr for FeatureThreshold {
+    type Err = color_eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (feature, at_least, value) = if let Some((feature, value)) = s.split_once(">=") {
+            (feature, true, value)
+        } else if let Some((feature, value)) = s.split_once("<=") {
+            (feature, false, value)
+        } else {
+            return Err(eyre!(
+                "threshold {:?} is not of the form name >= or <= value",
+                s
+            ));
+        };
+        let feature = feature.trim();
+        if !feature_names().contains(&feature) {
+            return Err(eyre!("unknown feature {:?} in threshold {:?}", feature, s));
+        }
+        Ok(Self {
+            feature: feature.to_string(),
+            at_least,
+            value: value
+                .trim()
+                .parse()
+                .map_err(|_| eyre!("invalid value in threshold {:?}", s))?,
+        })
+    }
+}
+
+/// Whether the track passes every threshold. Tracks without usable features never do, unless
+/// there are no thresholds at all.
+pub(crate) fn passes_thresholds(
+    db: &sled::Db,
+    track_id: &str,
+    thresholds: &[FeatureThreshold],
+) -> Result<bool> {
+    if thresholds.is_empty() {
+        return Ok(true);
+    }
+    let columns = match track_columns(db, track_id)? {
+        Some(columns) => columns,
+        None => return Ok(false),
+    };
+    Ok(thresholds.iter().all(|threshold| {
+        columns
+            .iter()
+            .find(|(name, _)| *name == threshold.feature)
+            .map_or(false, |(_, value)| {
+                if threshold.at_least {
+                    *value >= threshold.value
+                } else {
+                    *value <= threshold.value
+                }
+            })
+    }))
+}
+
+fn extras_tree(db: &sled::Db) -> Result<Option<sled::Tree>> {
+    Ok(if include_extras() {
+        Some(db.open_tree("track_extras")?)
+    } else {
+        None
+    })
+}
+
+fn no_rated_data(feature_name: &str) -> color_eyre::Report {
+    eyre!(
+        "no rated data available for feature {}, rate some tracks with audio features first",
+        feature_name
+    )
+}
 
 #[instrument(skip(db))]
 pub(crate) async fn feature_dataset_for_fitting(
@@ -11,43 +270,37 @@ pub(crate) async fn feature_dataset_for_fitting(
 ) -> Result<Dataset<f32, bool>> {
     let features_tree = db.open_tree("track_features")?;
     let feature_tree = db.open_tree(format!("input/{}", feature_name))?;
+    let extras_tree = extras_tree(&db)?;
+    let codec = Codec::of(&db)?;
     let mut features = vec![];
     let mut targets = vec![];
     for it in feature_tree.iter() {
         let (id, target_bytes) = it?;
-        if let Some(features_bytes) = features_tree.get(id)? {
-            let features_option: AudioFeatures = serde_json::from_slice(&features_bytes)?;
-            if let Some(features_object) = features_option {
-                features.extend_from_slice(&vec![
-                    features_object.acousticness,
-                    features_object.danceability,
-                    features_object.energy,
-                    features_object.instrumentalness,
-                    features_object.key as f32,
-                    features_object.liveness,
-                    features_object.loudness,
-                    features_object.speechiness,
-                    features_object.tempo,
-                    features_object.time_signature as f32,
-                    features_object.valence,
-                ]);
-                targets.push(target_bytes[0] > 0);
+        let target = Rating::decode(&target_bytes)?;
+        if target.is_skip() {
+            continue;
+        }
+        if let Some(features_bytes) = features_tree.get(&id)? {
+            let features_bytes = codec.decode(&features_bytes)?;
+            if let Some(record) = track_record(&id, &features_bytes, extras_tree.as_ref())? {
+                features.extend_from_slice(&record);
+                targets.push(target.rating > 0);
             }
         }
     }
-    let feature_names = vec![
-        "acousticness",
-        "danceability",
-        "energy",
-        "instrumentalness",
-        "key",
-        "liveness",
-        "loudness",
-        "speechiness",
-        "tempo",
-        "time_signature",
-        "valence",
-    ];
+    if targets.is_empty() {
+        return Err(no_rated_data(feature_name));
+    }
+    let positive = targets.iter().filter(|target| **target).count();
+    let negative = targets.len() - positive;
+    if positive == 0 || negative == 0 {
+        return Err(eyre!(
+            "need at least one positive and one negative example, have {} positive / {} negative",
+            positive,
+            negative
+        ));
+    }
+    let feature_names = feature_names();
     let dataset = Dataset::new(
         Array2::from_shape_vec((targets.len(), feature_names.len()), features)?,
         Array2::from_shape_vec((targets.len(), 1), targets)?,
@@ -57,43 +310,73 @@ pub(crate) async fn feature_dataset_for_fitting(
     Ok(dataset)
 }
 
-pub(crate) async fn feature_dataset_for_prediction(db: sled::Db) -> Result<Dataset<f32, String>> {
+/// Builds a dataset with the raw rating as a continuous target, e.g. for a 1–5 strength of fit.
+///
+/// Ratings are stored as a [`Rating`] in `input/{feature}` trees. The classification builder
+/// reads any nonzero rating as a positive example, while this one uses its value as is, so
+/// a scale where 0 means "doesn't fit at all" stays consistent between the two.
+// nothing fits a regression model yet, the builder is kept ready for graded features
+#[allow(dead_code)]
+#[instrument(skip(db))]
+pub(crate) async fn feature_dataset_for_regression(
+    db: sled::Db,
+    feature_name: &str,
+) -> Result<Dataset<f32, f32>> {
     let features_tree = db.open_tree("track_features")?;
+    let feature_tree = db.open_tree(format!("input/{}", feature_name))?;
+    let extras_tree = extras_tree(&db)?;
+    let codec = Codec::of(&db)?;
     let mut features = vec![];
     let mut targets = vec![];
+    for it in feature_tree.iter() {
+        let (id, target_bytes) = it?;
+        let target = Rating::decode(&target_bytes)?;
+        if target.is_skip() {
+            continue;
+        }
+        if let Some(features_bytes) = features_tree.get(&id)? {
+            let features_bytes = codec.decode(&features_bytes)?;
+            if let Some(record) = track_record(&id, &features_bytes, extras_tree.as_ref())? {
+                features.extend_from_slice(&record);
+                targets.push(target.rating as f32);
+            }
+        }
+    }
+    if targets.is_empty() {
+        return Err(no_rated_data(feature_name));
+    }
+    let feature_names = feature_names();
+    let dataset = Dataset::new(
+        Array2::from_shape_vec((targets.len(), feature_names.len()), features)?,
+        Array2::from_shape_vec((targets.len(), 1), targets)?,
+    )
+    .with_feature_names(feature_names);
+    info!(dim = ?dataset.records().dim());
+    Ok(dataset)
+}
+
+pub(crate) async fn feature_dataset_for_prediction(db: sled::Db) -> Result<Dataset<f32, String>> {
+    let features_tree = db.open_tree("track_features")?;
+    let extras_tree = extras_tree(&db)?;
+    let codec = Codec::of(&db)?;
+    let feature_names = feature_names();
+    // the whole library ends up in one matrix, so avoid growing it in steps
+    let capacity = features_tree.len();
+    let mut features = Vec::with_capacity(capacity * feature_names.len());
+    let mut targets = Vec::with_capacity(capacity);
     for it in features_tree.iter() {
         let (id_bytes, features_bytes) = it?;
-        let features_option: AudioFeatures = serde_json::from_slice(&features_bytes)?;
-        if let Some(features_object) = features_option {
-            features.extend_from_slice(&vec![
-                features_object.acousticness,
-                features_object.danceability,
-                features_object.energy,
-                features_object.instrumentalness,
-                features_object.key as f32,
-                features_object.liveness,
-                features_object.loudness,
-                features_object.speechiness,
-                features_object.tempo,
-                features_object.time_signature as f32,
-                features_object.valence,
-            ]);
+        let features_bytes = codec.decode(&features_bytes)?;
+        if let Some(record) = track_record(&id_bytes, &features_bytes, extras_tree.as_ref())? {
+            features.extend_from_slice(&record);
             targets.push(String::from_utf8_lossy(&id_bytes).to_string());
         }
     }
-    let feature_names = vec![
-        "acousticness",
-        "danceability",
-        "energy",
-        "instrumentalness",
-        "key",
-        "liveness",
-        "loudness",
-        "speechiness",
-        "tempo",
-        "time_signature",
-        "valence",
-    ];
+    if targets.is_empty() {
+        return Err(eyre!(
+            "no feature data available, populate the database before predicting"
+        ));
+    }
     let dataset = Dataset::new(
         Array2::from_shape_vec((targets.len(), feature_names.len()), features)?,
         Array2::from_shape_vec((targets.len(), 1), targets)?,
@@ -102,3 +385,835 @@ pub(crate) async fn feature_dataset_for_prediction(db: sled::Db) -> Result<Datas
     info!(dim = ?dataset.records().dim());
     Ok(dataset)
 }
+
+/// Number of bootstrapped trees making up a random forest.
+const FOREST_SIZE: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Algorithm {
+    DecisionTree,
+    RandomForest,
+}
+
+impl Algorithm {
+    /// Picks the algorithm named by `DEFY_MODEL`, defaulting to a single decision tree.
+    pub(crate) fn from_env() -> Result<Self> {
+        match std::env::var("DEFY_MODEL").as_deref() {
+            Err(_) | Ok("decision_tree") => Ok(Self::DecisionTree),
+            Ok("random_forest") => Ok(Self::RandomForest),
+            Ok(other) => Err(eyre!("unknown DEFY_MODEL {}", other)),
+        }
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Model {
+    DecisionTree(DecisionTree<f32, bool>),
+    RandomForest(Vec<DecisionTree<f32, bool>>),
+}
+
+impl Model {
+    pub(crate) fn algorithm(&self) -> Algorithm {
+        match self {
+            Self::DecisionTree(_) => Algorithm::DecisionTree,
+            Self::RandomForest(_) => Algorithm::RandomForest,
+        }
+    }
+
+    /// Fraction of trees voting for the positive class, for each record.
+    pub(crate) fn predict_probability(
+        &self,
+        records: &ArrayBase<impl Data<Elem = f32>, Ix2>,
+    ) -> Vec<f32> {
+        let records = records.to_owned();
+        let trees = match self {
+            Self::DecisionTree(tree) => std::slice::from_ref(tree),
+            Self::RandomForest(trees) => trees.as_slice(),
+        };
+        let mut votes = vec![0usize; records.nrows()];
+        for tree in trees {
+            for (vote, prediction) in votes.iter_mut().zip(tree.predict(&records).iter()) {
+                if *prediction {
+                    *vote += 1;
+                }
+            }
+        }
+        votes
+            .into_iter()
+            .map(|vote| vote as f32 / trees.len() as f32)
+            .collect()
+    }
+}
+
+/// How to counter a skewed ratio of positive to negative examples when fitting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Balance {
+    None,
+    /// Weigh each example inversely to the frequency of its class.
+    Weight,
+    /// Repeat randomly drawn minority class examples until both classes are equally common.
+    Oversample,
+}
+
+impl Balance {
+    /// Picks the strategy named by `DEFY_BALANCE`, defaulting to no balancing.
+    pub(crate) fn from_env() -> Result<Self> {
+        match std::env::var("DEFY_BALANCE").as_deref() {
+            Err(_) | Ok("none") => Ok(Self::None),
+            Ok("weight") => Ok(Self::Weight),
+            Ok("oversample") => Ok(Self::Oversample),
+            Ok(other) => Err(eyre!("unknown DEFY_BALANCE {}", other)),
+        }
+    }
+}
+
+fn balanced(dataset: &Dataset<f32, bool>, balance: Balance) -> Dataset<f32, bool> {
+    let targets = dataset.targets().column(0).to_vec();
+    let positive = targets.iter().filter(|target| **target).count();
+    let negative = targets.len() - positive;
+    let indices: Vec<usize> = match balance {
+        Balance::Oversample if positive > 0 && negative > 0 => {
+            let minority = positive < negative;
+            let minority_indices = (0..targets.len())
+                .filter(|i| targets[*i] == minority)
+                .collect_vec();
+            let mut rng = rand::thread_rng();
+            let extra = positive.max(negative) - positive.min(negative);
+            (0..targets.len())
+                .chain(
+                    (0..extra).map(|_| minority_indices[rng.gen_range(0..minority_indices.len())]),
+                )
+                .collect()
+        }
+        _ => (0..targets.len()).collect(),
+    };
+    let result = Dataset::new(
+        dataset.records().select(Axis(0), &indices),
+        dataset.targets().select(Axis(0), &indices),
+    );
+    match balance {
+        Balance::Weight if positive > 0 && negative > 0 => {
+            let total = targets.len() as f32;
+            result.with_weights(
+                targets
+                    .iter()
+                    .map(|target| {
+                        let count = if *target { positive } else { negative };
+                        total / (2.0 * count as f32)
+                    })
+                    .collect(),
+            )
+        }
+        _ => result,
+    }
+}
+
+#[instrument(skip(dataset))]
+pub(crate) fn fit(dataset: &Dataset<f32, bool>, algorithm: Algorithm) -> Result<Model> {
+    let dataset = balanced(dataset, Balance::from_env()?);
+    match algorithm {
+        Algorithm::DecisionTree => Ok(Model::DecisionTree(DecisionTree::params().fit(&dataset)?)),
+        Algorithm::RandomForest => {
+            let mut rng = rand::thread_rng();
+            let samples = dataset.nsamples();
+            let trees = (0..FOREST_SIZE)
+                .map(|_| {
+                    let indices: Vec<usize> =
+                        (0..samples).map(|_| rng.gen_range(0..samples)).collect();
+                    let mut bootstrap = Dataset::new(
+                        dataset.records().select(Axis(0), &indices),
+                        dataset.targets().select(Axis(0), &indices),
+                    );
+                    if let Some(weights) = dataset.weights() {
+                        bootstrap =
+                            bootstrap.with_weights(indices.iter().map(|i| weights[*i]).collect());
+                    }
+                    DecisionTree::params().fit(&bootstrap)
+                })
+                .collect::<std::result::Result<Vec<_>, _>>()?;
+            Ok(Model::RandomForest(trees))
+        }
+    }
+}
+
+/// Impurity-based importance of each dataset column, averaged over the model's trees, most
+/// important first.
+///
+/// These are tree importances rather than coefficients: every model here is a tree ensemble,
+/// and a split doesn't depend on the scale of its column, so no standardization is involved.
+pub(crate) fn tree_importance(model: &Model) -> Vec<(String, f32)> {
+    let trees = match model {
+        Model::DecisionTree(tree) => std::slice::from_ref(tree),
+        Model::RandomForest(trees) => trees.as_slice(),
+    };
+    let feature_names = feature_names();
+    let mut importance = vec![0f32; feature_names.len()];
+    for tree in trees {
+        for (total, value) in importance.iter_mut().zip(tree.feature_importance()) {
+            *total += value / trees.len() as f32;
+        }
+    }
+    feature_names
+        .into_iter()
+        .map(|name| name.to_string())
+        .zip(importance)
+        .sorted_by(|(_, a), (_, b)| b.total_cmp(a))
+        .collect()
+}
+
+/// Fits a model for the feature and scores every track with features, highest probability first.
+#[instrument(skip(db))]
+pub(crate) async fn score_tracks(db: sled::Db, feature_name: &str) -> Result<Vec<(String, f32)>> {
+    let dataset = feature_dataset_for_fitting(db.clone(), feature_name).await?;
+    let model = fit(&dataset, Algorithm::from_env()?)?;
+    info!(algorithm = ?model.algorithm(), "fitted model");
+    score_tracks_with(db, &model).await
+}
+
+/// Scores every track with features using an already fitted model, highest probability first.
+#[instrument(skip(db, model))]
+pub(crate) async fn score_tracks_with(db: sled::Db, model: &Model) -> Result<Vec<(String, f32)>> {
+    let all_dataset = feature_dataset_for_prediction(db).await?;
+    Ok(all_dataset
+        .targets()
+        .iter()
+        .cloned()
+        .zip(model.predict_probability(all_dataset.records()))
+        .sorted_by(|(_, a), (_, b)| b.total_cmp(a))
+        .collect())
+}
+
+/// Tracks scored together by [`accepted_tracks`].
+const SCORING_BATCH_SIZE: usize = 256;
+
+/// Ids of the tracks the model gives at least `threshold`, in the order of `track_features`.
+///
+/// Tracks are read and scored a batch at a time, so memory use doesn't grow with the library.
+pub(crate) fn accepted_tracks(
+    db: &sled::Db,
+    model: std::sync::Arc<Model>,
+    threshold: f32,
+) -> Result<impl Iterator<Item = Result<String>> + Send + 'static> {
+    let extras_tree = extras_tree(db)?;
+    let codec = Codec::of(db)?;
+    let mut records = db
+        .open_tree("track_features")?
+        .iter()
+        .map(move |it| -> Result<Option<(String, Vec<f32>)>> {
+            let (id, features_bytes) = it?;
+            let record = track_record(&id, &codec.decode(&features_bytes)?, extras_tree.as_ref())?;
+            Ok(record.map(|record| (String::from_utf8_lossy(&id).to_string(), record)))
+        })
+        .filter_map(Result::transpose);
+    let batches = std::iter::from_fn(move || {
+        let batch = records
+            .by_ref()
+            .take(SCORING_BATCH_SIZE)
+            .collect::<Result<Vec<_>>>();
+        match batch {
+            Ok(batch) if batch.is_empty() => None,
+            batch => Some(batch),
+        }
+    });
+    Ok(batches.flat_map(move |batch| {
+        let accepted = batch.and_then(|batch| {
+            let (ids, records): (Vec<_>, Vec<_>) = batch.into_iter().unzip();
+            let records =
+                Array2::from_shape_vec((ids.len(), feature_names().len()), records.concat())?;
+            Ok(ids
+                .into_iter()
+                .zip(model.predict_probability(&records))
+                .filter(|(_, probability)| *probability >= threshold)
+                .map(|(id, _)| id)
+                .collect_vec())
+        });
+        match accepted {
+            Ok(ids) => ids.into_iter().map(Ok).collect_vec(),
+            Err(error) => vec![Err(error)],
+        }
+    }))
+}
+
+/// Standardizes feature columns to zero mean and unit variance, so that no single feature
+/// dominates distances between tracks just because of its range.
+#[derive(Debug, Clone)]
+pub(crate) struct FeatureScaler {
+    mean: Array1<f32>,
+    std: Array1<f32>,
+}
+
+impl FeatureScaler {
+    pub(crate) fn fit<D: Data<Elem = f32>>(records: &ArrayBase<D, Ix2>) -> Self {
+        let mean = records
+            .mean_axis(Axis(0))
+            .unwrap_or_else(|| Array1::zeros(records.ncols()));
+        // constant columns are left unscaled rather than divided by zero
+        let std = records
+            .std_axis(Axis(0), 0.)
+            .mapv(|std| if std > 0. { std } else { 1. });
+        Self { mean, std }
+    }
+
+    pub(crate) fn transform<D: Data<Elem = f32>>(
+        &self,
+        records: &ArrayBase<D, Ix2>,
+    ) -> Array2<f32> {
+        (records - &self.mean) / &self.std
+    }
+}
+
+/// Groups every track with features into `k` clusters by k-means over the standardized
+/// features, returning the track ids of each cluster.
+#[instrument(skip(db))]
+pub(crate) async fn cluster_library(db: sled::Db, k: usize) -> Result<Vec<Vec<String>>> {
+    let all_dataset = feature_dataset_for_prediction(db).await?;
+    if k == 0 || k > all_dataset.nsamples() {
+        return Err(eyre!(
+            "cannot split {} tracks into {} clusters",
+            all_dataset.nsamples(),
+            k
+        ));
+    }
+    let records = FeatureScaler::fit(all_dataset.records()).transform(all_dataset.records());
+    let model = KMeans::params(k).fit(&DatasetBase::from(records.clone()))?;
+    let mut clusters = vec![vec![]; k];
+    for (id, cluster) in all_dataset.targets().iter().zip(model.predict(&records)) {
+        clusters[cluster].push(id.clone());
+    }
+    info!(sizes = ?clusters.iter().map(Vec::len).collect_vec(), "clustered library");
+    Ok(clusters)
+}
+
+/// Cluster assignments kept in the `clusters` tree, recomputed with [`cluster_library`] only
+/// when there are none yet or they were made for a different `k`.
+#[instrument(skip(db))]
+pub(crate) async fn stored_clusters(db: sled::Db, k: usize) -> Result<Vec<Vec<String>>> {
+    let clusters_tree = db.open_tree("clusters")?;
+    let meta_tree = db.open_tree("meta")?;
+    let stored_k = meta_tree
+        .get("clusters_k")?
+        .map(|bytes| -> Result<usize> { Ok(String::from_utf8_lossy(&bytes).parse()?) })
+        .transpose()?;
+    if stored_k == Some(k) {
+        let mut clusters = vec![vec![]; k];
+        for it in clusters_tree.iter() {
+            let (id, cluster) = it?;
+            let cluster: usize = String::from_utf8_lossy(&cluster).parse()?;
+            clusters[cluster].push(String::from_utf8_lossy(&id).to_string());
+        }
+        return Ok(clusters);
+    }
+    let clusters = cluster_library(db, k).await?;
+    clusters_tree.clear()?;
+    let mut assignments = sled::Batch::default();
+    for (cluster, ids) in clusters.iter().enumerate() {
+        for id in ids {
+            assignments.insert(id.as_bytes(), cluster.to_string().as_bytes());
+        }
+    }
+    clusters_tree.apply_batch(assignments)?;
+    meta_tree.insert("clusters_k", k.to_string().as_bytes())?;
+    Ok(clusters)
+}
+
+/// Ranks the tracks not rated for the feature by cosine similarity of their standardized
+/// features to the centroid of the positively rated ones, most similar first.
+#[instrument(skip(db))]
+pub(crate) async fn similar_tracks(db: sled::Db, feature_name: &str) -> Result<Vec<(String, f32)>> {
+    let feature_tree = db.open_tree(format!("input/{}", feature_name))?;
+    let all_dataset = feature_dataset_for_prediction(db).await?;
+    let records = FeatureScaler::fit(all_dataset.records()).transform(all_dataset.records());
+    let mut rated = vec![false; records.nrows()];
+    let mut centroid = Array1::<f32>::zeros(records.ncols());
+    let mut positive = 0usize;
+    for (i, id) in all_dataset.targets().iter().enumerate() {
+        if let Some(target) = feature_tree.get(id)? {
+            rated[i] = true;
+            let target = Rating::decode(&target)?;
+            if target.rating > 0 && !target.is_skip() {
+                centroid += &records.row(i);
+                positive += 1;
+            }
+        }
+    }
+    if positive == 0 {
+        return Err(eyre!(
+            "no positively rated tracks with audio features for feature {}",
+            feature_name
+        ));
+    }
+    centroid /= positive as f32;
+    let centroid_norm = centroid.dot(&centroid).sqrt();
+    Ok(all_dataset
+        .targets()
+        .iter()
+        .zip(records.outer_iter())
+        .zip(rated)
+        .filter(|(_, rated)| !rated)
+        .map(|((id, record), _)| {
+            let norm = record.dot(&record).sqrt() * centroid_norm;
+            let similarity = if norm > 0. {
+                record.dot(&centroid) / norm
+            } else {
+                0.
+            };
+            (id.clone(), similarity)
+        })
+        .sorted_by(|(_, a), (_, b)| b.total_cmp(a))
+        .collect())
+}
+
+/// Number of folds used when cross-validating a model.
+const CV_FOLDS: usize = 5;
+
+/// Probability from which a track is predicted to have a feature, unless asked otherwise.
+pub(crate) const DEFAULT_THRESHOLD: f32 = 0.5;
+
+/// The threshold set for the feature with [`store_threshold`], or [`DEFAULT_THRESHOLD`].
+pub(crate) fn stored_threshold(db: &sled::Db, feature_name: &str) -> Result<f32> {
+    match db
+        .open_tree(format!("models/{}", feature_name))?
+        .get("threshold")?
+    {
+        Some(bytes) => {
+            Ok(f32::from_be_bytes(bytes.as_ref().try_into().map_err(
+                |_| eyre!("malformed threshold of feature {}", feature_name),
+            )?))
+        }
+        None => Ok(DEFAULT_THRESHOLD),
+    }
+}
+
+/// Persists the probability from which tracks are predicted to have the feature.
+pub(crate) fn store_threshold(db: &sled::Db, feature_name: &str, threshold: f32) -> Result<()> {
+    if !(0.0..=1.0).contains(&threshold) {
+        return Err(eyre!("threshold {} is outside of 0–1", threshold));
+    }
+    db.open_tree(format!("models/{}", feature_name))?
+        .insert("threshold", &threshold.to_be_bytes())?;
+    Ok(())
+}
+
+/// Counts of cross-validation predictions by true and predicted class.
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+pub(crate) struct ConfusionMatrix {
+    pub(crate) true_positive: usize,
+    pub(crate) false_positive: usize,
+    pub(crate) false_negative: usize,
+    pub(crate) true_negative: usize,
+}
+
+/// Summary of a trained model, with accuracy estimated by cross-validation.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub(crate) struct ModelMetrics {
+    pub(crate) algorithm: Algorithm,
+    pub(crate) balance: Balance,
+    pub(crate) sample_count: usize,
+    pub(crate) positive: usize,
+    pub(crate) negative: usize,
+    pub(crate) accuracy: f32,
+    /// Probability from which predictions count as positive in `accuracy` and `confusion`.
+    pub(crate) threshold: f32,
+    pub(crate) confusion: ConfusionMatrix,
+}
+
+/// Out-of-fold positive class probabilities, paired with the true target of each example.
+#[instrument(skip(dataset))]
+pub(crate) fn cross_validated_probabilities(
+    dataset: &Dataset<f32, bool>,
+    algorithm: Algorithm,
+) -> Result<Vec<(bool, f32)>> {
+    let samples = dataset.nsamples();
+    if samples < CV_FOLDS {
+        return Err(eyre!(
+            "need at least {} examples for cross-validation, have {}",
+            CV_FOLDS,
+            samples
+        ));
+    }
+    let mut result = Vec::with_capacity(samples);
+    for fold in 0..CV_FOLDS {
+        let (test, train): (Vec<usize>, Vec<usize>) =
+            (0..samples).partition(|i| i % CV_FOLDS == fold);
+        let train_set = Dataset::new(
+            dataset.records().select(Axis(0), &train),
+            dataset.targets().select(Axis(0), &train),
+        );
+        let model = fit(&train_set, algorithm)?;
+        let probabilities = model.predict_probability(&dataset.records().select(Axis(0), &test));
+        result.extend(
+            test.iter()
+                .map(|i| dataset.targets()[[*i, 0]])
+                .zip(probabilities),
+        );
+    }
+    Ok(result)
+}
+
+/// Cross-validates the algorithm on the dataset, reporting its class distribution.
+#[instrument(skip(dataset))]
+pub(crate) fn evaluate(
+    dataset: &Dataset<f32, bool>,
+    algorithm: Algorithm,
+    threshold: f32,
+) -> Result<ModelMetrics> {
+    let predictions = cross_validated_probabilities(dataset, algorithm)?;
+    let mut confusion = ConfusionMatrix::default();
+    for (target, probability) in predictions.iter() {
+        match (*target, *probability >= threshold) {
+            (true, true) => confusion.true_positive += 1,
+            (false, true) => confusion.false_positive += 1,
+            (true, false) => confusion.false_negative += 1,
+            (false, false) => confusion.true_negative += 1,
+        }
+    }
+    let correct = confusion.true_positive + confusion.true_negative;
+    let positive = dataset.targets().iter().filter(|target| **target).count();
+    Ok(ModelMetrics {
+        algorithm,
+        balance: Balance::from_env()?,
+        sample_count: dataset.nsamples(),
+        positive,
+        negative: dataset.nsamples() - positive,
+        accuracy: correct as f32 / predictions.len() as f32,
+        threshold,
+        confusion,
+    })
+}
+
+/// One decision threshold on an ROC curve with the rates it results in.
+#[derive(Debug, Clone, serde::Serialize)]
+pub(crate) struct RocPoint {
+    /// Lowest probability accepted, `None` for the starting point accepting nothing.
+    pub(crate) threshold: Option<f32>,
+    pub(crate) false_positive_rate: f32,
+    pub(crate) true_positive_rate: f32,
+}
+
+/// Tradeoff between true and false positives over all decision thresholds.
+#[derive(Debug, Clone, serde::Serialize)]
+pub(crate) struct RocCurve {
+    pub(crate) points: Vec<RocPoint>,
+    pub(crate) auc: f32,
+}
+
+/// Builds the ROC curve of the algorithm from cross-validated probabilities.
+#[instrument(skip(dataset))]
+pub(crate) fn roc_curve(dataset: &Dataset<f32, bool>, algorithm: Algorithm) -> Result<RocCurve> {
+    let predictions = cross_validated_probabilities(dataset, algorithm)?
+        .into_iter()
+        .sorted_by(|(_, a), (_, b)| b.total_cmp(a))
+        .collect_vec();
+    let positive = predictions.iter().filter(|(target, _)| *target).count();
+    let negative = predictions.len() - positive;
+    if positive == 0 || negative == 0 {
+        return Err(eyre!(
+            "an ROC curve needs both positive and negative examples, have {} positive / {} negative",
+            positive,
+            negative
+        ));
+    }
+    let mut points = vec![RocPoint {
+        threshold: None,
+        false_positive_rate: 0.0,
+        true_positive_rate: 0.0,
+    }];
+    let (mut true_positive, mut false_positive) = (0usize, 0usize);
+    for (threshold, group) in &predictions.iter().group_by(|(_, probability)| *probability) {
+        for (target, _) in group {
+            if *target {
+                true_positive += 1;
+            } else {
+                false_positive += 1;
+            }
+        }
+        points.push(RocPoint {
+            threshold: Some(threshold),
+            false_positive_rate: false_positive as f32 / negative as f32,
+            true_positive_rate: true_positive as f32 / positive as f32,
+        });
+    }
+    let auc = points
+        .iter()
+        .tuple_windows()
+        .map(|(a, b)| {
+            (b.false_positive_rate - a.false_positive_rate)
+                * (a.true_positive_rate + b.true_positive_rate)
+                / 2.0
+        })
+        .sum();
+    Ok(RocCurve { points, auc })
+}
+
+/// Description of the model persisted for a feature, telling how stale it is.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub(crate) struct ModelMetadata {
+    pub(crate) trained_at: chrono::DateTime<chrono::Utc>,
+    pub(crate) sample_count: usize,
+    /// Fraction of the training examples that are positive.
+    pub(crate) class_balance: f32,
+    pub(crate) algorithm: Algorithm,
+    pub(crate) metrics: ModelMetrics,
+    /// Dataset columns the model was trained on, which the records it scores must match.
+    #[serde(default)]
+    pub(crate) columns: Vec<String>,
+}
+
+/// Fits a model for the feature, evaluates it and persists it in the `models/{feature}` tree,
+/// together with its [`ModelMetadata`].
+#[instrument(skip(db))]
+pub(crate) async fn train_model(
+    db: sled::Db,
+    feature_name: &str,
+    algorithm: Algorithm,
+) -> Result<(Model, ModelMetrics)> {
+    let dataset = feature_dataset_for_fitting(db.clone(), feature_name).await?;
+    let metrics = evaluate(&dataset, algorithm, stored_threshold(&db, feature_name)?)?;
+    let model = fit(&dataset, algorithm)?;
+    let metadata = ModelMetadata {
+        trained_at: chrono::Utc::now(),
+        sample_count: metrics.sample_count,
+        class_balance: metrics.positive as f32 / metrics.sample_count as f32,
+        algorithm,
+        metrics: metrics.clone(),
+        columns: feature_names().into_iter().map(str::to_string).collect(),
+    };
+    let models_tree = db.open_tree(format!("models/{}", feature_name))?;
+    models_tree.insert("model", serde_json::to_vec(&model)?)?;
+    models_tree.insert("metadata", serde_json::to_vec(&metadata)?)?;
+    info!(?metrics, "trained model");
+    Ok((model, metrics))
+}
+
+/// Loads the model last persisted by [`train_model`] for the feature, if any.
+///
+/// A model trained on other columns than the current ones, e.g. before `DEFY_EXTRA_FEATURES`
+/// was changed, would read the wrong columns, so it is refused until retrained.
+pub(crate) fn stored_model(db: &sled::Db, feature_name: &str) -> Result<Option<Model>> {
+    let model_bytes = match db
+        .open_tree(format!("models/{}", feature_name))?
+        .get("model")?
+    {
+        Some(model_bytes) => model_bytes,
+        None => return Ok(None),
+    };
+    let columns = stored_model_metadata(db, feature_name)?
+        .map(|metadata| metadata.columns)
+        .unwrap_or_default();
+    if !columns.iter().map(String::as_str).eq(feature_names()) {
+        return Err(eyre!(
+            "model of feature {} was trained on columns {:?} instead of {:?}, retrain it",
+            feature_name,
+            columns,
+            feature_names()
+        ));
+    }
+    Ok(Some(serde_json::from_slice(&model_bytes)?))
+}
+
+/// Loads the metadata of the model last persisted by [`train_model`] for the feature, if any.
+pub(crate) fn stored_model_metadata(
+    db: &sled::Db,
+    feature_name: &str,
+) -> Result<Option<ModelMetadata>> {
+    match db
+        .open_tree(format!("models/{}", feature_name))?
+        .get("metadata")?
+    {
+        Some(metadata_bytes) => Ok(Some(serde_json::from_slice(&metadata_bytes)?)),
+        None => Ok(None),
+    }
+}
+
+/// Trains a model for every feature, skipping those that cannot be trained yet.
+#[instrument(skip(db))]
+pub(crate) async fn train_all_models(db: sled::Db) -> Result<Vec<(String, ModelMetrics)>> {
+    let algorithm = Algorithm::from_env()?;
+    let features = db
+        .tree_names()
+        .into_iter()
+        .filter_map(|name| {
+            String::from_utf8_lossy(&name)
+                .strip_prefix("input/")
+                .map(|feature| feature.to_string())
+        })
+        .collect_vec();
+    let mut result = vec![];
+    for feature in features {
+        match train_model(db.clone(), &feature, algorithm).await {
+            Ok((_, metrics)) => result.push((feature, metrics)),
+            Err(error) => warn!(%feature, %error, "skipping feature"),
+        }
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::temporary_db;
+
+    /// Stores the audio features of `track{i}` for every rating and rates it for `comfy`.
+    fn rated_library(db: &sled::Db, ratings: &[u8]) {
+        let features_tree = db.open_tree("track_features").unwrap();
+        let feature_tree = db.open_tree("input/comfy").unwrap();
+        for (i, rating) in ratings.iter().enumerate() {
+            let track_id = format!("track{}", i);
+            let features = crate::source::mock::audio_features(&track_id);
+            features_tree
+                .insert(&track_id, serde_json::to_vec(&features).unwrap())
+                .unwrap();
+            feature_tree
+                .insert(&track_id, &Rating::now(*rating).encode())
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn stored_model_with_other_columns_is_refused() {
+        let db = temporary_db();
+        let metrics = ModelMetrics {
+            algorithm: Algorithm::DecisionTree,
+            balance: Balance::None,
+            sample_count: 2,
+            positive: 1,
+            negative: 1,
+            accuracy: 1.0,
+            threshold: 0.5,
+            confusion: ConfusionMatrix::default(),
+        };
+        let metadata = ModelMetadata {
+            trained_at: chrono::Utc::now(),
+            sample_count: 2,
+            class_balance: 0.5,
+            algorithm: Algorithm::DecisionTree,
+            metrics,
+            columns: vec!["danceability".to_string()],
+        };
+        let models_tree = db.open_tree("models/comfy").unwrap();
+        models_tree.insert("model", "{}").unwrap();
+        models_tree
+            .insert("metadata", serde_json::to_vec(&metadata).unwrap())
+            .unwrap();
+        let error = stored_model(&db, "comfy").unwrap_err();
+        assert!(error.to_string().contains("retrain"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn fitting_needs_both_classes() {
+        for (ratings, expected) in [
+            (&[1, 2, 3][..], "have 3 positive / 0 negative"),
+            (&[0, 0][..], "have 0 positive / 2 negative"),
+        ] {
+            let db = temporary_db();
+            rated_library(&db, ratings);
+            let error = feature_dataset_for_fitting(db, "comfy").await.unwrap_err();
+            assert!(error.to_string().contains(expected), "{}", error);
+        }
+    }
+
+    #[tokio::test]
+    async fn prediction_dataset_holds_a_large_library() {
+        let db = temporary_db();
+        let features_tree = db.open_tree("track_features").unwrap();
+        for i in 0..10_000 {
+            let track_id = format!("track{}", i);
+            let features = crate::source::mock::audio_features(&track_id);
+            features_tree
+                .insert(&track_id, serde_json::to_vec(&features).unwrap())
+                .unwrap();
+        }
+        let dataset = feature_dataset_for_prediction(db).await.unwrap();
+        assert_eq!(dataset.records().dim(), (10_000, feature_names().len()));
+        assert_eq!(dataset.targets().len(), 10_000);
+    }
+
+    #[tokio::test]
+    async fn empty_datasets_are_refused() {
+        let db = temporary_db();
+        let error = feature_dataset_for_prediction(db.clone())
+            .await
+            .unwrap_err();
+        assert!(
+            error.to_string().contains("no feature data available"),
+            "{}",
+            error
+        );
+        rated_library(&db, &[SKIP_RATING]);
+        let error = feature_dataset_for_fitting(db, "comfy").await.unwrap_err();
+        assert!(
+            error.to_string().contains("no rated data available"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn undetected_key_sets_key_unknown() {
+        let record_with_key = |key: i32| {
+            let mut features =
+                serde_json::to_value(crate::source::mock::audio_features("track1")).unwrap();
+            features["key"] = key.into();
+            track_record(b"track1", &serde_json::to_vec(&features).unwrap(), None)
+                .unwrap()
+                .unwrap()
+        };
+        let key = FEATURE_NAMES
+            .iter()
+            .position(|name| *name == "key")
+            .unwrap();
+        let key_unknown = FEATURE_NAMES.len()
+            + DERIVED_FEATURE_NAMES
+                .iter()
+                .position(|name| *name == "key_unknown")
+                .unwrap();
+        let unknown = record_with_key(UNKNOWN_KEY);
+        assert_eq!((unknown[key], unknown[key_unknown]), (0., 1.));
+        let known = record_with_key(5);
+        assert_eq!((known[key], known[key_unknown]), (5., 0.));
+    }
+
+    #[tokio::test]
+    async fn regression_targets_are_the_stored_ratings() {
+        let db = temporary_db();
+        rated_library(&db, &[0, 3, 5, SKIP_RATING, 1]);
+        // a legacy rating is a single byte without a timestamp
+        db.open_tree("input/comfy")
+            .unwrap()
+            .insert("track4", &[2])
+            .unwrap();
+        let dataset = feature_dataset_for_regression(db, "comfy").await.unwrap();
+        assert_eq!(
+            dataset.targets().iter().copied().collect::<Vec<_>>(),
+            vec![0., 3., 5., 2.]
+        );
+    }
+
+    #[tokio::test]
+    async fn accepted_tracks_are_scored_over_a_large_library() {
+        let db = temporary_db();
+        rated_library(&db, &[0, 1, 0, 1]);
+        let dataset = feature_dataset_for_fitting(db.clone(), "comfy")
+            .await
+            .unwrap();
+        let model = std::sync::Arc::new(fit(&dataset, Algorithm::DecisionTree).unwrap());
+        let features_tree = db.open_tree("track_features").unwrap();
+        for i in 0..10_000 {
+            let track_id = format!("library{}", i);
+            let features = crate::source::mock::audio_features(&track_id);
+            features_tree
+                .insert(&track_id, serde_json::to_vec(&features).unwrap())
+                .unwrap();
+        }
+        let accepted = accepted_tracks(&db, model.clone(), 0.)
+            .unwrap()
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(accepted.len(), 10_004);
+        assert!(accepted.windows(2).all(|pair| pair[0] < pair[1]));
+        assert_eq!(accepted_tracks(&db, model, 1.1).unwrap().count(), 0);
+    }
+}
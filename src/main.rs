@@ -1,70 +1,491 @@
-use std::{collections::VecDeque, iter::FromIterator, str::FromStr};
+use std::{
+    cell::Cell,
+    collections::{hash_map::DefaultHasher, HashSet, VecDeque},
+    hash::{Hash, Hasher},
+    iter::FromIterator,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use clap::Parser;
 
-use color_eyre::Result;
-use futures_util::StreamExt;
+use client_error::{with_retries, ErrorKind};
+use color_eyre::{
+    eyre::{eyre, WrapErr},
+    Result,
+};
+use compression::Codec;
+use futures_util::{StreamExt, TryStreamExt};
 use itertools::Itertools;
-use kickstart::Client;
-use linfa::prelude::*;
-use linfa_trees::DecisionTree;
-use rspotify::{
-    clients::{BaseClient, OAuthClient},
-    model::{
-        FullAlbum, FullTrack, Id, PlayableId, PlayableItem, PlaylistId, SavedAlbum,
-        SimplifiedTrack, TrackId,
-    },
+use rspotify::model::{
+    FullAlbum, FullTrack, Id, Image, PlayableId, PlayableItem, PlaylistId, SavedAlbum, SavedTrack,
+    SimplifiedTrack, TrackId,
 };
 use sled::Db;
-use tracing::{info, instrument};
+use source::SpotifySource;
+use tracing::{debug, error, info, instrument, warn, Span};
 
+mod backup;
+mod client_error;
+mod compression;
+mod config;
 mod data_input;
+mod governor;
 mod kickstart;
 mod learning;
+mod schema;
+mod source;
+mod stats;
+
+/// Playlist the rolling playlist and the database are populated from.
+pub(crate) const MAIN_PLAYLIST_ID: &str = "6CmOKM7D0nvMM1h1GQTl1L";
+/// Playlist receiving the latest additions to the main playlist.
+pub(crate) const ROLLING_PLAYLIST_ID: &str = "02S7eexioL9T1xWOP53hlK";
+/// Playlist receiving the tracks predicted to be comfy.
+const COMFY_PLAYLIST_ID: &str = "4FV2Z1R15FBlQruwI6HO6z";
+
+/// What the rolling playlist's selection depends on besides the main playlist itself.
+#[derive(Debug, Default)]
+pub(crate) struct RollingSettings {
+    pub(crate) thresholds: Vec<learning::FeatureThreshold>,
+    pub(crate) contributors: HashSet<String>,
+}
+
+impl RollingSettings {
+    pub(crate) fn load() -> Result<Self> {
+        let config = config::Config::load()?;
+        Ok(Self {
+            thresholds: config.rolling_filters()?,
+            contributors: config.priority_contributors(),
+        })
+    }
+
+    /// Changes whenever the settings do, so that a sync isn't skipped after changing them.
+    fn fingerprint(&self) -> String {
+        let mut hasher = DefaultHasher::new();
+        format!("{:?}", self.thresholds).hash(&mut hasher);
+        self.contributors
+            .iter()
+            .sorted()
+            .collect_vec()
+            .hash(&mut hasher);
+        format!("{:016x}", hasher.finish())
+    }
+}
+
+/// Tracks of the main playlist that belong in the rolling playlist: the 100 most recently added
+/// passing the configured audio feature thresholds, with those added by the configured priority
+/// contributors ahead of the rest.
+pub(crate) fn rolling_selection<'a>(
+    main_playlist: &'a [PlaylistTrack],
+    db: &Db,
+    settings: &RollingSettings,
+) -> Result<Vec<&'a FullTrack>> {
+    let RollingSettings {
+        thresholds,
+        contributors,
+    } = settings;
+    if !thresholds.is_empty() {
+        info!(?thresholds, "filtering rolling playlist by audio features");
+    }
+    if !contributors.is_empty() {
+        info!(?contributors, "prioritizing rolling playlist contributors");
+    }
+    let is_prioritized = |item: &PlaylistTrack| {
+        item.added_by
+            .as_ref()
+            .map_or(false, |user| contributors.contains(user))
+    };
+    // latest additions first, with tracks of unknown age counting as the oldest; among equal
+    // timestamps the playlist order decides
+    let mut by_recency = main_playlist.iter().rev().collect_vec();
+    by_recency.sort_by(|a, b| b.added_at.cmp(&a.added_at));
+    let (prioritized, others): (Vec<&PlaylistTrack>, Vec<&PlaylistTrack>) = by_recency
+        .into_iter()
+        .partition(|item| is_prioritized(item));
+    let mut selection = Vec::with_capacity(100);
+    for item in prioritized.into_iter().chain(others) {
+        if selection.len() == 100 {
+            break;
+        }
+        if learning::passes_thresholds(db, item.track.id.id(), thresholds)? {
+            selection.push(&item.track);
+        }
+    }
+    Ok(selection)
+}
 
 #[instrument(skip(client, db))]
-async fn perform_update(client: &Client, db: Db) -> Result<()> {
-    let main_playlist = fetch_playlist(
-        &client,
-        &PlaylistId::from_str("6CmOKM7D0nvMM1h1GQTl1L").unwrap(),
+async fn perform_update(client: &impl SpotifySource, db: Db) -> Result<()> {
+    let main_playlist_id = PlaylistId::from_id(MAIN_PLAYLIST_ID).unwrap();
+    let meta_tree = db.open_tree("meta")?;
+    let snapshot_id = client
+        .playlist_snapshot_id(&main_playlist_id)
+        .await
+        .map_err(|error| {
+            log_client_error(&error, &format!("playlist {}", main_playlist_id.id()));
+            error
+        })?;
+    let force_sync = std::env::var("DEFY_FORCE_SYNC").is_ok();
+    let settings = RollingSettings::load()?;
+    let fingerprint = settings.fingerprint();
+    if !force_sync
+        && meta_tree.get("source_snapshot_id")?.as_deref() == Some(snapshot_id.as_bytes())
+        && meta_tree.get("rolling_settings")?.as_deref() == Some(fingerprint.as_bytes())
+    {
+        info!(
+            %snapshot_id,
+            "main playlist and rolling settings unchanged, skipping rolling playlist sync"
+        );
+    } else {
+        let main_playlist = fetch_playlist_items(client, &main_playlist_id).await?;
+
+        let selection = rolling_selection(&main_playlist, &db, &settings)?;
+        let rolling_playlist_id = PlaylistId::from_id(ROLLING_PLAYLIST_ID).unwrap();
+        guard_track_count(&rolling_playlist_id, selection.len())?;
+        log_selection_changes(
+            &db,
+            "rolling",
+            selection
+                .iter()
+                .map(|track| (track.id.id().to_string(), track.name.clone()))
+                .collect(),
+        )?;
+        let reduced_tracks: Vec<&dyn PlayableId> = selection
+            .into_iter()
+            .map(|track| &track.id as &dyn PlayableId)
+            .collect();
+        write_playlist(
+            client,
+            &rolling_playlist_id,
+            reduced_tracks,
+            WriteMode::Replace,
+        )
+        .await?;
+        if !is_dry_run() {
+            meta_tree.insert("source_snapshot_id", snapshot_id.as_bytes())?;
+            meta_tree.insert("rolling_settings", fingerprint.as_bytes())?;
+        }
+        info!(%snapshot_id, force_sync, "rolling playlist synced");
+    }
+
+    let rejected_comfy = std::env::var("DEFY_COMFY_REJECTED_PLAYLIST")
+        .ok()
+        .map(|id| PlaylistId::from_id(&id))
+        .transpose()?;
+    generate_playlist(
+        client,
+        db,
+        "comfy",
+        &PlaylistId::from_id(COMFY_PLAYLIST_ID).unwrap(),
+        rejected_comfy.as_ref(),
     )
     .await?;
+    Ok(())
+}
 
-    let reduced_tracks: Vec<&dyn PlayableId> = main_playlist
-        .iter()
-        .rev()
-        .take(100)
-        .map(|track| &track.id as &dyn PlayableId)
+/// Lowest scoring tracks written to a rejected playlist, unless `DEFY_REJECTED_TRACKS` says
+/// otherwise.
+const DEFAULT_REJECTED_TRACKS: usize = 50;
+
+/// Writes the tracks a feature's model accepts to `selected`, best scoring first.
+///
+/// If `rejected` is given, the lowest scoring of the tracks the model doesn't accept are written
+/// there for review, worst first.
+#[instrument(skip(client, db))]
+async fn generate_playlist(
+    client: &impl SpotifySource,
+    db: Db,
+    feature: &str,
+    selected: &PlaylistId,
+    rejected: Option<&PlaylistId>,
+) -> Result<()> {
+    let scored: Vec<(TrackId, f32)> = learning::score_tracks(db.clone(), feature)
+        .await?
+        .into_iter()
+        .map(|(id, probability)| (TrackId::from_str(&id).unwrap(), probability))
         .collect();
+    let threshold = learning::stored_threshold(&db, feature)?;
+    let accepted = scored
+        .iter()
+        .take_while(|(_, probability)| *probability >= threshold)
+        .count();
+    let recommended = if std::env::var("DEFY_RECOMMENDATIONS").is_ok() {
+        recommend_tracks(client, &db, feature, &scored[..accepted]).await?
+    } else {
+        vec![]
+    };
+    guard_track_count(selected, accepted + recommended.len())?;
+    let lowest = match rejected {
+        Some(rejected) => {
+            let count = std::env::var("DEFY_REJECTED_TRACKS")
+                .map_or(Ok(DEFAULT_REJECTED_TRACKS), |count| count.parse())
+                .wrap_err("cannot parse DEFY_REJECTED_TRACKS")?;
+            let lowest = scored[accepted..].iter().rev().take(count).collect_vec();
+            // checked before anything is written, so that neither playlist changes alone
+            guard_track_count(rejected, lowest.len())?;
+            lowest
+        }
+        None => vec![],
+    };
+    let details_tree = db.open_tree("track_details")?;
+    let codec = Codec::of(&db)?;
+    let selection = scored[..accepted]
+        .iter()
+        .map(|(track, _)| track)
+        .chain(recommended.iter())
+        .map(|track| {
+            let name = match details_tree.get(track.id())? {
+                Some(details) => {
+                    serde_json::from_slice::<SimplifiedTrack>(&codec.decode(&details)?)?.name
+                }
+                None => String::new(),
+            };
+            Ok((track.id().to_string(), name))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    log_selection_changes(&db, feature, selection)?;
     write_playlist(
-        &client,
-        &PlaylistId::from_str("02S7eexioL9T1xWOP53hlK").unwrap(),
-        reduced_tracks,
+        client,
+        selected,
+        scored[..accepted]
+            .iter()
+            .map(|(track, _)| track)
+            .chain(recommended.iter())
+            .map(|track| track as &dyn PlayableId),
+        WriteMode::from_env()?,
     )
     .await?;
+    if let Some(rejected) = rejected {
+        write_playlist(
+            client,
+            rejected,
+            lowest
+                .into_iter()
+                .map(|(track, _)| track as &dyn PlayableId),
+            WriteMode::Replace,
+        )
+        .await?;
+    }
+    Ok(())
+}
 
-    let comfy_dataset = learning::feature_dataset_for_fitting(db.clone(), "comfy").await?;
-    let comfy_tree = DecisionTree::params().fit(&comfy_dataset)?;
-    let all_dataset = learning::feature_dataset_for_prediction(db).await?;
-    let predicted_comfy: Vec<TrackId> = all_dataset
-        .targets()
+/// Logs which tracks entered and left a generated playlist since the previous update and
+/// remembers the new selection in `meta` for the next one.
+#[instrument(skip(db, selection))]
+fn log_selection_changes(db: &Db, playlist: &str, selection: Vec<(String, String)>) -> Result<()> {
+    let meta_tree = db.open_tree("meta")?;
+    let key = format!("selection/{}", playlist);
+    match meta_tree.get(&key)? {
+        Some(previous) => {
+            let previous: Vec<(String, String)> = serde_json::from_slice(&previous)?;
+            let contains =
+                |tracks: &[(String, String)], id: &str| tracks.iter().any(|(track, _)| track == id);
+            for (id, name) in selection.iter().filter(|(id, _)| !contains(&previous, id)) {
+                info!(%id, %name, "track added");
+            }
+            for (id, name) in previous.iter().filter(|(id, _)| !contains(&selection, id)) {
+                info!(%id, %name, "track removed");
+            }
+        }
+        None => info!(
+            tracks = selection.len(),
+            "no previous selection to compare with"
+        ),
+    }
+    if !is_dry_run() {
+        meta_tree.insert(key, serde_json::to_vec(&selection)?)?;
+    }
+    Ok(())
+}
+
+/// Most seed tracks a recommendations request accepts.
+const RECOMMENDATION_SEEDS: usize = 5;
+/// Most tracks a recommendations request returns.
+const RECOMMENDATIONS_LIMIT: u32 = 100;
+
+/// Tracks Spotify recommends from the best scoring accepted ones that the feature's model
+/// accepts too, best scoring first, leaving out those already accepted.
+///
+/// The features of recommended tracks are kept in `track_features` for later runs.
+#[instrument(skip(client, db, accepted))]
+async fn recommend_tracks(
+    client: &impl SpotifySource,
+    db: &Db,
+    feature: &str,
+    accepted: &[(TrackId, f32)],
+) -> Result<Vec<TrackId>> {
+    let seeds = accepted
         .iter()
-        .zip(comfy_tree.predict(all_dataset.records()).into_raw_vec())
-        .filter(|(_, prediction)| *prediction)
-        .map(|(id, _)| TrackId::from_str(id).unwrap())
+        .take(RECOMMENDATION_SEEDS)
+        .map(|(track, _)| track)
+        .collect_vec();
+    if seeds.is_empty() {
+        return Ok(vec![]);
+    }
+    let recommended: HashSet<TrackId> = client
+        .recommendations(seeds, RECOMMENDATIONS_LIMIT)
+        .await
+        .map_err(|error| {
+            log_client_error(&error, "recommendations");
+            error
+        })?
+        .into_iter()
+        .filter_map(|track| track.id)
+        .filter(|id| !accepted.iter().any(|(track, _)| track == id))
         .collect();
-    write_playlist(
-        &client,
-        &PlaylistId::from_str("4FV2Z1R15FBlQruwI6HO6z").unwrap(),
-        predicted_comfy.iter().map(|track| track as &dyn PlayableId),
-    )
-    .await?;
-    Ok(())
+    let features_db = db.open_tree("track_features")?;
+    let missing = recommended
+        .iter()
+        .filter(|id| !features_db.contains_key(id.id()).unwrap_or(false))
+        .cloned()
+        .collect_vec();
+    fetch_features(client, db, &missing).await?;
+    let threshold = learning::stored_threshold(db, feature)?;
+    let result = learning::score_tracks(db.clone(), feature)
+        .await?
+        .into_iter()
+        .take_while(|(_, probability)| *probability >= threshold)
+        .map(|(id, _)| TrackId::from_str(&id).unwrap())
+        .filter(|id| recommended.contains(id))
+        .collect_vec();
+    info!(
+        recommended = recommended.len(),
+        accepted = result.len(),
+        "scored recommendations"
+    );
+    Ok(result)
+}
+
+/// Logs a failed Spotify request, warning with the `Retry-After` value when rate limited.
+fn log_client_error(error: &rspotify::ClientError, context: &str) {
+    match client_error::classify(error) {
+        ErrorKind::RateLimited { retry_after } => {
+            warn!(context, ?retry_after, "rate limited by Spotify")
+        }
+        _ => debug!(context, %error, "Spotify request failed"),
+    }
+}
+
+/// Most tracks a single populate run stores, from `DEFY_POPULATE_LIMIT`, for quick smoke tests.
+fn populate_limit() -> Result<Option<usize>> {
+    std::env::var("DEFY_POPULATE_LIMIT")
+        .ok()
+        .map(|limit| limit.parse())
+        .transpose()
+        .wrap_err("cannot parse DEFY_POPULATE_LIMIT")
+}
+
+/// Fewest tracks a generated playlist is written with, unless `DEFY_MIN_PLAYLIST_TRACKS` says
+/// otherwise.
+const DEFAULT_MIN_PLAYLIST_TRACKS: usize = 1;
+
+/// Refuses to write a generated playlist with suspiciously few tracks, which more likely comes
+/// from a failed fetch than from the library, unless `DEFY_FORCE_WRITE` is set.
+pub(crate) fn guard_track_count(playlist: &PlaylistId, count: usize) -> Result<()> {
+    let minimum = std::env::var("DEFY_MIN_PLAYLIST_TRACKS")
+        .map_or(Ok(DEFAULT_MIN_PLAYLIST_TRACKS), |minimum| minimum.parse())
+        .wrap_err("cannot parse DEFY_MIN_PLAYLIST_TRACKS")?;
+    if count >= minimum {
+        return Ok(());
+    }
+    if std::env::var("DEFY_FORCE_WRITE").is_ok() {
+        warn!(
+            playlist = %playlist.id(),
+            count,
+            minimum,
+            "writing playlist below the minimum track count as forced"
+        );
+        return Ok(());
+    }
+    warn!(
+        playlist = %playlist.id(),
+        count,
+        minimum,
+        "refusing to write playlist below the minimum track count"
+    );
+    Err(eyre!(
+        "playlist {} would get {} tracks, fewer than the minimum of {}, \
+         set DEFY_FORCE_WRITE to write it anyway",
+        playlist.id(),
+        count,
+        minimum
+    ))
+}
+
+/// Whether Spotify playlists should be left untouched, with writes only logged.
+fn is_dry_run() -> bool {
+    std::env::var("DEFY_DRY_RUN").is_ok()
+}
+
+/// Reads a port number from the given environment variable, defaulting to 3000.
+pub(crate) fn port_from_env(name: &str) -> u16 {
+    std::env::var(name).map_or(3000, |port| {
+        port.parse()
+            .unwrap_or_else(|_| panic!("{} is not a valid port", name))
+    })
+}
+
+/// Opens the URL in a browser, or only logs it when `DEFY_NO_BROWSER` is set or no browser can
+/// be started, e.g. on a headless server.
+pub(crate) fn open_in_browser(url: &str) {
+    if std::env::var("DEFY_NO_BROWSER").is_ok() {
+        info!(%url, "visit this URL to continue");
+        return;
+    }
+    if let Err(error) = webbrowser::open(url) {
+        warn!(%error, %url, "cannot open a browser, visit this URL to continue");
+    }
+}
+
+/// Namespaces a path for the given profile by suffixing its file stem with the profile name,
+/// e.g. `db` becomes `db-work`. The default profile keeps the path as is.
+pub(crate) fn profiled_path(path: &Path, profile: Option<&str>) -> PathBuf {
+    let profile = match profile {
+        Some(profile) => profile,
+        None => return path.to_path_buf(),
+    };
+    let mut file_name = path.file_stem().unwrap_or_default().to_os_string();
+    file_name.push("-");
+    file_name.push(profile);
+    if let Some(extension) = path.extension() {
+        file_name.push(".");
+        file_name.push(extension);
+    }
+    path.with_file_name(file_name)
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
+    let args = Args::parse();
     {
         use tracing_error::ErrorLayer;
-        use tracing_subscriber::{fmt, prelude::*, EnvFilter};
+        use tracing_subscriber::{fmt, fmt::writer::BoxMakeWriter, prelude::*, EnvFilter};
+        let log_file = std::env::var("DEFY_LOG_FILE").ok();
+        let writer = match &log_file {
+            Some(path) => {
+                let file = std::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)?;
+                BoxMakeWriter::new(move || file.try_clone().expect("cannot clone log file"))
+            }
+            None => BoxMakeWriter::new(std::io::stdout),
+        };
+        let (json_layer, text_layer) = match std::env::var("DEFY_LOG_FORMAT").as_deref() {
+            Ok("json") => (Some(fmt::layer().json().with_writer(writer)), None),
+            Ok("pretty") | Err(_) => (
+                None,
+                Some(
+                    fmt::layer()
+                        .with_ansi(log_file.is_none())
+                        .with_writer(writer),
+                ),
+            ),
+            Ok(other) => panic!("unknown DEFY_LOG_FORMAT {}", other),
+        };
         tracing_subscriber::registry()
             .with(ErrorLayer::default())
             .with(
@@ -72,97 +493,446 @@ async fn main() -> Result<()> {
                     .or_else(|_| EnvFilter::try_new("info,rspotify_http=warn"))
                     .unwrap(),
             )
-            .with(fmt::layer())
+            .with(json_layer)
+            .with(text_layer)
             .init();
     }
     color_eyre::install()?;
 
     info!("obtaining client");
-    let client = kickstart::kickstart().await?;
+    let client = kickstart::kickstart(args.profile.as_deref()).await?;
+    let governor = governor::Governor::from_env()?;
+    let spotify = source::Reauthorizing::new(
+        source::Governed::new(client.clone(), governor.clone()),
+        client.clone(),
+    );
     info!("opening database");
-    let db = sled::open("db").unwrap();
-    if std::env::var("SKIP_POPULATING").is_ok() {
-        info!("skipping database populating")
-    } else {
-        info!("populating database");
-        populate_database(&client, db.clone()).await?;
+    let db = open_database(&profiled_path(&args.db_path, args.profile.as_deref())).await?;
+    schema::migrate(&db)?;
+    compression::setup(&db)?;
+    match args.command {
+        None => {
+            if std::env::var("SKIP_POPULATING").is_ok() {
+                info!("skipping database populating")
+            } else {
+                info!("populating database");
+                populate_database(&spotify, db.clone()).await?;
+            }
+            info!("launching data input interface");
+            data_input::web_interface(db.clone(), client.clone(), governor.clone(), args.bind)
+                .await?;
+            flush_database(&db).await?;
+            info!("performing programmed actions");
+            perform_update(&spotify, db.clone()).await?;
+        }
+        Some(Command::Populate) => populate_database(&spotify, db.clone()).await?,
+        Some(Command::PopulateDetails) => populate_track_details(&spotify, db.clone()).await?,
+        Some(Command::PopulateFeatures) => populate_track_features(&spotify, db.clone()).await?,
+        Some(Command::Serve) => {
+            data_input::web_interface(db.clone(), client.clone(), governor.clone(), args.bind)
+                .await?
+        }
+        Some(Command::Update) => perform_update(&spotify, db.clone()).await?,
+        Some(Command::Train { feature }) => train(db.clone(), &feature).await?,
+        Some(Command::TrainAll) => {
+            for (feature, metrics) in learning::train_all_models(db.clone()).await? {
+                info!(%feature, ?metrics, "trained model");
+            }
+        }
+        Some(Command::ClusterPlaylists { k, clusters }) => {
+            write_cluster_playlists(&spotify, db.clone(), k, &clusters).await?
+        }
+    }
+    flush_database(&db).await?;
+
+    Ok(())
+}
+
+/// Writes out everything buffered by sled, so that ratings survive the process being killed.
+pub(crate) async fn flush_database(db: &Db) -> sled::Result<()> {
+    let flushed = db.flush_async().await?;
+    info!(flushed, "flushed database");
+    Ok(())
+}
+
+/// How many times opening a database locked by another process is attempted.
+const DB_OPEN_ATTEMPTS: u32 = 3;
+
+/// Database settings, leaving sled's defaults in place unless `DEFY_SLED_CACHE_MB` or
+/// `DEFY_SLED_FLUSH_MS` is set, with `DEFY_SLED_FLUSH_MS=0` turning background flushes off.
+fn sled_config(path: &Path) -> Result<sled::Config> {
+    let mut config = sled::Config::new().path(path);
+    if let Ok(cache_mb) = std::env::var("DEFY_SLED_CACHE_MB") {
+        let cache_mb: u64 = cache_mb
+            .parse()
+            .wrap_err("cannot parse DEFY_SLED_CACHE_MB")?;
+        info!(cache_mb, "configuring database cache");
+        config = config.cache_capacity(cache_mb * 1024 * 1024);
+    }
+    if let Ok(flush_ms) = std::env::var("DEFY_SLED_FLUSH_MS") {
+        let flush_ms: u64 = flush_ms
+            .parse()
+            .wrap_err("cannot parse DEFY_SLED_FLUSH_MS")?;
+        info!(flush_ms, "configuring database flushes");
+        config = config.flush_every_ms(Some(flush_ms).filter(|&flush_ms| flush_ms > 0));
     }
-    info!("launching data input interface");
-    data_input::web_interface(db.clone(), client.clone()).await?;
-    info!("performing programmed actions");
-    perform_update(&client, db.clone()).await?;
+    Ok(config)
+}
 
+#[instrument]
+async fn open_database(path: &Path) -> Result<Db> {
+    let config = sled_config(path)?;
+    for attempt in 1.. {
+        match config.open() {
+            Ok(db) => return Ok(db),
+            Err(sled::Error::Io(error))
+                if error.kind() == std::io::ErrorKind::WouldBlock
+                    || error.to_string().contains("lock") =>
+            {
+                if attempt == DB_OPEN_ATTEMPTS {
+                    return Err(eyre!(
+                        "another defy instance appears to be running against the database at {}",
+                        path.display()
+                    ));
+                }
+                warn!(attempt, "database is locked, retrying");
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            }
+            Err(error) => return Err(error.into()),
+        }
+    }
+    unreachable!()
+}
+
+#[derive(Debug, clap::Parser)]
+#[clap(about, version)]
+struct Args {
+    /// Path of the sled database
+    #[clap(long, global = true, default_value = "db")]
+    db_path: PathBuf,
+    /// Address the data input interface listens on
+    #[clap(long, global = true, default_value = "127.0.0.1")]
+    bind: IpAddr,
+    /// Account profile, keeping a separate database and token cache for each name
+    #[clap(long, global = true, env = "DEFY_PROFILE")]
+    profile: Option<String>,
+    /// Phase to run, all of them in order when omitted
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, clap::Subcommand)]
+enum Command {
+    /// Fetch tracks and their audio features into the database
+    Populate,
+    /// Fetch only the details of the library's tracks
+    PopulateDetails,
+    /// Fetch only the audio features of stored tracks lacking them
+    PopulateFeatures,
+    /// Run the data input interface until it is shut down
+    Serve,
+    /// Rewrite the generated playlists
+    Update,
+    /// Fit a model for a feature and report which audio features drive it
+    Train { feature: String },
+    /// Fit and store a model for every feature with enough ratings
+    TrainAll,
+    /// Write k-means clusters of the library to playlists named `defy cluster N`
+    ClusterPlaylists {
+        /// Number of clusters to split the library into
+        #[clap(short, default_value = "8")]
+        k: usize,
+        /// Clusters to write, all of them if none are given
+        clusters: Vec<usize>,
+    },
+}
+
+/// Writes each selected non-empty cluster to its own playlist, creating the playlists on first
+/// use and remembering them in `meta`.
+#[instrument(skip(client, db))]
+async fn write_cluster_playlists(
+    client: &impl SpotifySource,
+    db: Db,
+    k: usize,
+    selected: &[usize],
+) -> Result<()> {
+    let meta_tree = db.open_tree("meta")?;
+    let clusters = learning::stored_clusters(db, k).await?;
+    for (cluster, track_ids) in clusters.iter().enumerate() {
+        if !selected.is_empty() && !selected.contains(&cluster) {
+            continue;
+        }
+        if track_ids.is_empty() {
+            info!(cluster, "skipping empty cluster");
+            continue;
+        }
+        let name = format!("defy cluster {}", cluster);
+        let key = format!("cluster_playlist/{}", cluster);
+        let playlist = match meta_tree.get(&key)? {
+            Some(id) => PlaylistId::from_id(&String::from_utf8_lossy(&id))?,
+            None if is_dry_run() => {
+                info!(%name, tracks = track_ids.len(), "would create playlist");
+                continue;
+            }
+            None => {
+                let playlist = client.create_playlist(&name).await.map_err(|error| {
+                    log_client_error(&error, &format!("creating playlist {}", name));
+                    error
+                })?;
+                meta_tree.insert(&key, playlist.id().as_bytes())?;
+                info!(%name, id = playlist.id(), "created playlist");
+                playlist
+            }
+        };
+        let tracks = track_ids
+            .iter()
+            .map(|id| TrackId::from_id(id))
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        write_playlist(
+            client,
+            &playlist,
+            tracks.iter().map(|track| track as &dyn PlayableId),
+            WriteMode::from_env()?,
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+#[instrument(skip(db))]
+async fn train(db: Db, feature: &str) -> Result<()> {
+    let (model, metrics) =
+        learning::train_model(db, feature, learning::Algorithm::from_env()?).await?;
+    info!(
+        ?metrics,
+        importance = ?learning::tree_importance(&model),
+        "trained model"
+    );
     Ok(())
 }
 
+/// A track of a playlist together with when and by whom it was added, which collaborative
+/// playlists tell apart.
+#[derive(Debug, Clone)]
+pub(crate) struct PlaylistTrack {
+    pub(crate) track: FullTrack,
+    pub(crate) added_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub(crate) added_by: Option<String>,
+}
+
 #[instrument(skip(client))]
-async fn fetch_playlist(client: &Client, id: &PlaylistId) -> Result<Vec<FullTrack>> {
-    use rspotify::{model::PlaylistItem, ClientError};
-    let result: Vec<Result<PlaylistItem, ClientError>> =
-        client.playlist_items(id, None, None).collect().await;
-    let result: Result<Vec<PlaylistItem>, ClientError> = result.into_iter().collect();
-    Ok(result?
+pub(crate) async fn fetch_playlist(
+    client: &impl SpotifySource,
+    id: &PlaylistId,
+) -> Result<Vec<FullTrack>> {
+    Ok(fetch_playlist_items(client, id)
+        .await?
+        .into_iter()
+        .map(|item| item.track)
+        .collect())
+}
+
+#[instrument(skip(client), fields(track_count = tracing::field::Empty))]
+pub(crate) async fn fetch_playlist_items(
+    client: &impl SpotifySource,
+    id: &PlaylistId,
+) -> Result<Vec<PlaylistTrack>> {
+    let (tracks, unavailable): (Vec<PlaylistTrack>, Vec<PlaylistTrack>) = client
+        .playlist_items(id)
+        .await
+        .map_err(|error| {
+            log_client_error(&error, &format!("items of playlist {}", id.id()));
+            error
+        })?
         .into_iter()
         .filter_map(|item| {
-            if let Some(PlayableItem::Track(track)) = &item.track {
-                Some(track.clone())
+            if let Some(PlayableItem::Track(track)) = item.track {
+                Some(PlaylistTrack {
+                    track,
+                    added_at: item.added_at,
+                    added_by: item.added_by.map(|user| user.id.id().to_string()),
+                })
             } else {
                 None
             }
         })
-        .collect())
+        // local files have no Spotify ID and tracks pulled from the catalog can't be played,
+        // so neither can be written back to a playlist
+        .partition(|item| !item.track.is_local && item.track.is_playable != Some(false));
+    if !unavailable.is_empty() {
+        warn!(
+            dropped = unavailable.len(),
+            "dropped local or unavailable playlist tracks"
+        );
+    }
+    Span::current().record("track_count", &tracks.len());
+    Ok(tracks)
 }
 
-#[instrument(skip(client, album), fields(album.id = ?album.id, album.title = ?album.name))]
-async fn fetch_album_tracks(client: &Client, album: &FullAlbum) -> Result<Vec<SimplifiedTrack>> {
-    use rspotify::ClientError;
-    let result: Vec<Result<SimplifiedTrack, ClientError>> =
-        client.album_track(&album.id).collect().await;
-    let result: Result<Vec<SimplifiedTrack>, ClientError> = result.into_iter().collect();
-    Ok(result?)
+#[instrument(
+    skip(client, album),
+    fields(album.id = ?album.id, album.title = ?album.name, track_count = tracing::field::Empty)
+)]
+async fn fetch_album_tracks(
+    client: &impl SpotifySource,
+    album: &FullAlbum,
+) -> Result<Vec<SimplifiedTrack>> {
+    let tracks = client.album_tracks(&album.id).await?;
+    Span::current().record("track_count", &tracks.len());
+    Ok(tracks)
 }
 
-#[instrument(skip(client))]
-async fn fetch_library_album_tracks(client: &Client) -> Result<Vec<SimplifiedTrack>> {
-    let albums = fetch_library_albums(client).await?;
-    let mut result = vec![];
-    for album in albums {
-        result.extend(fetch_album_tracks(client, &album.album).await?);
-    }
-    Ok(result)
+#[instrument(skip(client), fields(album_count = tracing::field::Empty))]
+async fn fetch_library_albums(client: &impl SpotifySource) -> Result<Vec<SavedAlbum>> {
+    let albums = client.saved_albums().await?;
+    Span::current().record("album_count", &albums.len());
+    Ok(albums)
 }
 
-#[instrument(skip(client))]
-async fn fetch_library_albums(client: &Client) -> Result<Vec<SavedAlbum>> {
-    use rspotify::ClientError;
-    let result: Vec<Result<SavedAlbum, ClientError>> =
-        client.current_user_saved_albums(None).collect().await;
-    let result: Result<Vec<SavedAlbum>, ClientError> = result.into_iter().collect();
-    Ok(result?)
+#[instrument(skip(client), fields(track_count = tracing::field::Empty))]
+async fn fetch_saved_tracks(client: &impl SpotifySource) -> Result<Vec<SavedTrack>> {
+    let tracks = client.saved_tracks().await?;
+    Span::current().record("track_count", &tracks.len());
+    Ok(tracks)
+}
+
+/// How [`write_playlist`] treats the tracks already in a playlist.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum WriteMode {
+    /// Leave the playlist with exactly the given tracks.
+    Replace,
+    /// Keep the current tracks and add the given ones missing from it after them.
+    Append,
+}
+
+impl WriteMode {
+    /// Picks the mode named by `DEFY_WRITE_MODE` for generated feature playlists, defaulting to
+    /// replacing their contents.
+    fn from_env() -> Result<Self> {
+        match std::env::var("DEFY_WRITE_MODE").as_deref() {
+            Err(_) | Ok("replace") => Ok(Self::Replace),
+            Ok("append") => Ok(Self::Append),
+            Ok(other) => Err(eyre!("unknown DEFY_WRITE_MODE {}", other)),
+        }
+    }
 }
 
 #[instrument(skip(client, tracks))]
-async fn write_playlist<'a>(
-    client: &Client,
+pub(crate) async fn write_playlist<'a>(
+    client: &impl SpotifySource,
     id: &PlaylistId,
     tracks: impl IntoIterator<Item = &'a dyn PlayableId> + Send + 'a,
+    mode: WriteMode,
 ) -> Result<()> {
-    client.playlist_replace_items(id, vec![]).await?;
     let mut tracks = VecDeque::from_iter(tracks.into_iter());
-    for i in 0.. {
-        if tracks.is_empty() {
-            break;
+    if is_dry_run() {
+        for (i, batch) in tracks.iter().chunks(100).into_iter().enumerate() {
+            let batch = batch.map(|track| track.uri()).collect_vec();
+            info!(
+                offset = i * 100,
+                ?mode,
+                ?batch,
+                "dry run, not writing batch"
+            );
         }
+        return Ok(());
+    }
 
-        client
-            .playlist_add_items(id, tracks.drain(0..(tracks.len().min(100))), Some(i * 100))
-            .await?;
+    let snapshot = fetch_playlist(client, id).await?;
+    info!(
+        snapshot_count = snapshot.len(),
+        "snapshotted playlist before writing"
+    );
+    let current: HashSet<String> = snapshot.iter().map(|track| track.id.uri()).collect();
+    let desired: HashSet<String> = tracks.iter().map(|track| track.uri()).collect();
+    let removed = snapshot
+        .iter()
+        .filter(|track| !desired.contains(&track.id.uri()))
+        .map(|track| &track.id as &dyn PlayableId)
+        .collect_vec();
+    let added = tracks
+        .iter()
+        .copied()
+        .filter(|track| !current.contains(&track.uri()))
+        .collect_vec();
+    if added.is_empty() && (removed.is_empty() || mode == WriteMode::Append) {
+        info!("playlist already up to date, not writing");
+        return Ok(());
     }
-    Ok(())
+
+    // tracks added so far, reported on failure to tell how far the playlist got
+    let mut written = 0;
+    // each batch is retried on its own while rate limited, so a failure midway never repeats
+    // the batches already written
+    let result: Result<()> = if mode == WriteMode::Append {
+        info!(added = added.len(), "appending to playlist");
+        async {
+            // the snapshot leaves out local and unavailable tracks, so it can't tell where the
+            // playlist ends; without a position Spotify appends at the end
+            for batch in added.chunks(100) {
+                with_retries(|| client.playlist_add_items(id, batch.to_vec(), None)).await?;
+                written += batch.len();
+            }
+            Ok(())
+        }
+        .await
+    } else if removed.len() + added.len() < tracks.len() {
+        info!(
+            removed = removed.len(),
+            added = added.len(),
+            "converging playlist by diff"
+        );
+        async {
+            for batch in removed.chunks(100) {
+                with_retries(|| {
+                    client.playlist_remove_all_occurrences_of_items(id, batch.to_vec())
+                })
+                .await?;
+            }
+            for batch in added.chunks(100) {
+                with_retries(|| client.playlist_add_items(id, batch.to_vec(), None)).await?;
+                written += batch.len();
+            }
+            Ok(())
+        }
+        .await
+    } else {
+        async {
+            with_retries(|| client.playlist_replace_items(id, vec![])).await?;
+            for (i, batch) in tracks.make_contiguous().chunks(100).enumerate() {
+                let position = Some((i * 100) as u32);
+                with_retries(|| client.playlist_add_items(id, batch.to_vec(), position)).await?;
+                written += batch.len();
+            }
+            Ok(())
+        }
+        .await
+    };
+    if let Some(error) = result
+        .as_ref()
+        .err()
+        .and_then(|error| error.downcast_ref::<rspotify::ClientError>())
+    {
+        log_client_error(error, &format!("writing playlist {}", id.id()));
+    }
+    if result.is_err() {
+        let original = snapshot.iter().map(|track| track.id.uri()).collect_vec();
+        error!(
+            ?original,
+            "writing playlist failed, original contents listed for recovery"
+        );
+    }
+    result.wrap_err_with(|| {
+        format!(
+            "writing playlist failed after adding {} tracks, {} original tracks were logged",
+            written,
+            snapshot.len()
+        )
+    })
 }
 
 #[instrument(skip(track), fields(track.id = ?track.id))]
-fn simplify_track(track: FullTrack) -> SimplifiedTrack {
+pub(crate) fn simplify_track(track: FullTrack) -> Option<SimplifiedTrack> {
     let FullTrack {
         artists,
         available_markets,
@@ -181,7 +951,11 @@ fn simplify_track(track: FullTrack) -> SimplifiedTrack {
         track_number,
         ..
     } = track;
-    SimplifiedTrack {
+    if is_local {
+        // local files have no stable Spotify ID to key them by
+        return None;
+    }
+    Some(SimplifiedTrack {
         artists,
         available_markets: Some(available_markets),
         disc_number,
@@ -197,61 +971,575 @@ fn simplify_track(track: FullTrack) -> SimplifiedTrack {
         name,
         preview_url,
         track_number,
+    })
+}
+
+/// Maximum number of tracks per audio features request, as limited by the Spotify API.
+const FEATURES_BATCH_SIZE: usize = 100;
+/// Number of album track requests kept in flight at once, unless `DEFY_ALBUM_CONCURRENCY` says
+/// otherwise.
+const DEFAULT_ALBUM_CONCURRENCY: usize = 8;
+
+fn album_concurrency() -> Result<usize> {
+    let concurrency = std::env::var("DEFY_ALBUM_CONCURRENCY")
+        .map_or(Ok(DEFAULT_ALBUM_CONCURRENCY), |concurrency| {
+            concurrency.parse()
+        })
+        .wrap_err("cannot parse DEFY_ALBUM_CONCURRENCY")?;
+    if concurrency == 0 {
+        return Err(eyre!("DEFY_ALBUM_CONCURRENCY must be at least 1"));
     }
+    Ok(concurrency)
 }
 
-#[instrument(skip(client, db))]
-async fn populate_database(client: &Client, db: Db) -> Result<()> {
-    info!("fetching main playlist");
-    let main_playlist = fetch_playlist(
-        &client,
-        &PlaylistId::from_str("6CmOKM7D0nvMM1h1GQTl1L").unwrap(),
+/// Number of audio features requests kept in flight at once.
+const FEATURES_CONCURRENCY: usize = 4;
+/// `meta` key marking the main playlist as stored by an unfinished populate run.
+const POPULATE_PLAYLIST_MARKER: &str = "populate/main_playlist";
+/// `meta` key prefix marking library albums stored by an unfinished populate run.
+const POPULATE_ALBUM_MARKER_PREFIX: &str = "populate/album/";
+/// `meta` key marking saved tracks as stored by an unfinished populate run.
+const POPULATE_SAVED_TRACKS_MARKER: &str = "populate/saved_tracks";
+
+/// Fetches and stores the audio features of the tracks, returning how many were stored.
+#[instrument(
+    skip(client, db, track_ids),
+    fields(track_count = track_ids.len(), features_stored = tracing::field::Empty)
+)]
+async fn fetch_features(
+    client: &impl SpotifySource,
+    db: &Db,
+    track_ids: &[TrackId],
+) -> Result<usize> {
+    let features_db = db.open_tree("track_features")?;
+    let codec = Codec::of(db)?;
+    // every page is written as soon as it arrives so an abort keeps the finished ones
+    let stored = futures_util::stream::iter(
+        track_ids
+            .chunks(FEATURES_BATCH_SIZE)
+            .map(<[TrackId]>::to_vec),
     )
-    .await?
-    .into_iter()
-    .map(simplify_track);
+    .map(|page| async move {
+        let featuresets = client
+            .tracks_features(&page)
+            .await
+            .map_err(|error| {
+                log_client_error(&error, &format!("features of {} tracks", page.len()));
+                error
+            })?
+            .unwrap_or(vec![]);
+        Ok::<_, color_eyre::Report>((page, featuresets))
+    })
+    .buffer_unordered(FEATURES_CONCURRENCY)
+    .try_fold(0usize, |fetched, (page, featuresets)| {
+        let features_db = &features_db;
+        async move {
+            let mut batch = sled::Batch::default();
+            let mut stored = 0usize;
+            for (track_id, featureset) in page.iter().zip(featuresets) {
+                batch.insert(
+                    track_id.id(),
+                    codec.encode(&serde_json::to_vec(&featureset)?)?,
+                );
+                stored += 1;
+            }
+            features_db.apply_batch(batch)?;
+            stats::forget_tracks_with_features(db)?;
+            Ok::<_, color_eyre::Report>(fetched + stored)
+        }
+    })
+    .await?;
+    Span::current().record("features_stored", &stored);
+    Ok(stored)
+}
 
-    info!("fetching library album tracks");
-    let library = fetch_library_album_tracks(&client).await?;
-    let all_tracks: Vec<SimplifiedTrack> = main_playlist
-        .into_iter()
-        .chain(library.into_iter())
-        .filter(|track| track.id.is_some())
-        .collect();
+/// Stores the library's track details, then the audio features of tracks lacking them.
+///
+/// `SKIP_POPULATING_DETAILS` and `SKIP_POPULATING_FEATURES` leave out either phase.
+async fn populate_database(client: &impl SpotifySource, db: Db) -> Result<()> {
+    if std::env::var("SKIP_POPULATING_DETAILS").is_ok() {
+        info!("skipping track details");
+    } else {
+        populate_track_details(client, db.clone()).await?;
+    }
+    if std::env::var("SKIP_POPULATING_FEATURES").is_ok() {
+        info!("skipping track features");
+    } else {
+        populate_track_features(client, db).await?;
+    }
+    Ok(())
+}
+
+#[instrument(skip(client, db), fields(tracks_stored = tracing::field::Empty))]
+async fn populate_track_details(client: &impl SpotifySource, db: Db) -> Result<()> {
     let tracks_db = db.open_tree("track_details")?;
-    for track in all_tracks.iter() {
-        tracks_db
-            .insert(
-                track.id.clone().unwrap().id(),
-                serde_json::to_vec(track).unwrap(),
+    let extras_db = db.open_tree("track_extras")?;
+    let added_by_db = db.open_tree("track_added_by")?;
+    let meta_tree = db.open_tree("meta")?;
+    let codec = Codec::of(&db)?;
+    let album_art_db = db.open_tree("album_art")?;
+    let remaining = Cell::new(populate_limit()?);
+    if let Some(limit) = remaining.get() {
+        warn!(
+            limit,
+            "DEFY_POPULATE_LIMIT is set, storing only the first tracks"
+        );
+    }
+    let limit_reached = || remaining.get() == Some(0);
+    // returns whether every track was stored, which it might not be once the limit is reached
+    let store_tracks =
+        |tracks: Vec<(SimplifiedTrack, learning::TrackExtras, Vec<Image>)>| -> Result<bool> {
+            let mut tracks = tracks
+                .into_iter()
+                .filter(|(track, _, _)| !track.is_local)
+                .collect_vec();
+            let complete = match remaining.get() {
+                Some(limit) if limit < tracks.len() => {
+                    tracks.truncate(limit);
+                    false
+                }
+                _ => true,
+            };
+            if let Some(limit) = remaining.get() {
+                remaining.set(Some(limit - tracks.len()));
+            }
+            let mut details = sled::Batch::default();
+            let mut extras = sled::Batch::default();
+            let mut album_art = sled::Batch::default();
+            for (track, track_extras, images) in &tracks {
+                if let Some(id) = &track.id {
+                    details.insert(id.id(), codec.encode(&serde_json::to_vec(track)?)?);
+                    extras.insert(id.id(), serde_json::to_vec(track_extras)?);
+                    if !images.is_empty() {
+                        album_art.insert(id.id(), serde_json::to_vec(images)?);
+                    }
+                }
+            }
+            tracks_db.apply_batch(details)?;
+            extras_db.apply_batch(extras)?;
+            album_art_db.apply_batch(album_art)?;
+            Ok(complete)
+        };
+
+    // progress markers let an interrupted run skip whatever was already stored
+    if meta_tree.contains_key(POPULATE_PLAYLIST_MARKER)? {
+        info!("main playlist already stored, skipping");
+    } else {
+        info!("fetching main playlist");
+        let main_playlist =
+            fetch_playlist_items(client, &PlaylistId::from_id(MAIN_PLAYLIST_ID).unwrap()).await?;
+        let mut contributors = sled::Batch::default();
+        for item in main_playlist.iter().filter(|item| !item.track.is_local) {
+            if let Some(user) = &item.added_by {
+                contributors.insert(item.track.id.id(), user.as_bytes());
+            }
+        }
+        added_by_db.apply_batch(contributors)?;
+        let main_playlist = main_playlist
+            .into_iter()
+            .filter_map(|PlaylistTrack { track, .. }| {
+                let extras = learning::TrackExtras::new(
+                    track.popularity,
+                    track.album.release_date.as_deref(),
+                );
+                let images = track.album.images.clone();
+                simplify_track(track).map(|track| (track, extras, images))
+            })
+            .collect();
+        if store_tracks(main_playlist)? {
+            meta_tree.insert(POPULATE_PLAYLIST_MARKER, sled::IVec::default())?;
+        }
+    }
+
+    let album_concurrency = album_concurrency()?;
+    info!(album_concurrency, "fetching library album tracks");
+    let albums = if limit_reached() {
+        vec![]
+    } else {
+        fetch_library_albums(client).await?
+    };
+    let mut skipped_albums = 0usize;
+    let mut pending = vec![];
+    for album in albums {
+        let marker = format!("{}{}", POPULATE_ALBUM_MARKER_PREFIX, album.album.id.id());
+        if meta_tree.contains_key(&marker)? {
+            skipped_albums += 1;
+        } else {
+            pending.push((album, marker));
+        }
+    }
+    // albums are stored in library order as their tracks arrive, while later ones are fetched
+    let mut fetched =
+        futures_util::stream::iter(pending.iter().map(|(album, marker)| async move {
+            (
+                album,
+                marker,
+                fetch_album_tracks(client, &album.album).await,
             )
-            .unwrap();
+        }))
+        .buffered(album_concurrency);
+    while let Some((album, marker, tracks)) = fetched.next().await {
+        if limit_reached() {
+            break;
+        }
+        let extras =
+            learning::TrackExtras::new(album.album.popularity, Some(&album.album.release_date));
+        let tracks = tracks?;
+        let complete = store_tracks(
+            tracks
+                .into_iter()
+                .map(|track| (track, extras.clone(), album.album.images.clone()))
+                .collect(),
+        )?;
+        if complete {
+            meta_tree.insert(marker.as_bytes(), sled::IVec::default())?;
+        }
+    }
+    if skipped_albums > 0 {
+        info!(skipped_albums, "resumed library fetch");
+    }
+
+    if std::env::var("DEFY_SAVED_TRACKS").is_err() {
+        debug!("not including saved tracks");
+    } else if limit_reached() {
+        info!("population limit reached, skipping saved tracks");
+    } else if meta_tree.contains_key(POPULATE_SAVED_TRACKS_MARKER)? {
+        info!("saved tracks already stored, skipping");
+    } else {
+        info!("fetching saved tracks");
+        // tracks already stored from the playlist or an album keep their details and extras
+        let saved_tracks = fetch_saved_tracks(client)
+            .await?
+            .into_iter()
+            .filter(|saved| !tracks_db.contains_key(saved.track.id.id()).unwrap_or(false))
+            .filter_map(|saved| {
+                let extras = learning::TrackExtras::new(
+                    saved.track.popularity,
+                    saved.track.album.release_date.as_deref(),
+                );
+                let images = saved.track.album.images.clone();
+                simplify_track(saved.track).map(|track| (track, extras, images))
+            })
+            .collect_vec();
+        info!(new_tracks = saved_tracks.len(), "storing saved tracks");
+        if store_tracks(saved_tracks)? {
+            meta_tree.insert(POPULATE_SAVED_TRACKS_MARKER, sled::IVec::default())?;
+        }
+    }
+
+    Span::current().record("tracks_stored", &tracks_db.len());
+
+    // the run completed, so the next one should look at the library afresh
+    meta_tree.remove(POPULATE_PLAYLIST_MARKER)?;
+    meta_tree.remove(POPULATE_SAVED_TRACKS_MARKER)?;
+    for marker in meta_tree.scan_prefix(POPULATE_ALBUM_MARKER_PREFIX).keys() {
+        meta_tree.remove(marker?)?;
     }
 
+    Ok(())
+}
+
+/// Fetches the audio features of every stored track that has none yet.
+#[instrument(skip(client, db), fields(features_stored = tracing::field::Empty))]
+async fn populate_track_features(client: &impl SpotifySource, db: Db) -> Result<()> {
     info!("fetching missing features");
+    let tracks_db = db.open_tree("track_details")?;
     let features_db = db.open_tree("track_features")?;
-    let mut fetched_features = 0usize;
-    for page in &tracks_db
-        .into_iter()
+    let missing = tracks_db
+        .iter()
         .map(Result::unwrap)
         .map(|(key, _value)| key)
         .filter(|key| !features_db.contains_key(key).unwrap())
-        .chunks(100)
-    {
-        let page = page
-            .map(|key| TrackId::from_id(std::str::from_utf8(&key).unwrap()).unwrap())
-            .collect_vec();
-        for (track_id, featureset) in page
-            .iter()
-            .zip(client.tracks_features(&page).await?.unwrap_or(vec![]))
-        {
-            features_db
-                .insert(track_id.id(), serde_json::to_vec(&featureset).unwrap())
-                .unwrap();
-        }
-        fetched_features += page.len();
+        .map(|key| TrackId::from_id(std::str::from_utf8(&key).unwrap()).unwrap())
+        .collect_vec();
+    let fetched_features = fetch_features(client, &db, &missing).await?;
+    let (stored_features, unique_tracks) = (features_db.len(), tracks_db.len());
+    if stored_features != unique_tracks {
+        warn!(
+            stored_features,
+            unique_tracks, "stored features do not cover every track"
+        );
     }
     info!(?fetched_features);
+    Span::current().record("features_stored", &stored_features);
 
     Ok(())
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::source::mock::{self, MockSource, Write};
+
+    /// A database that lives only as long as the test.
+    pub(crate) fn temporary_db() -> Db {
+        sled::Config::new().temporary(true).open().unwrap()
+    }
+
+    fn source_with_playlist(id: &str, items: Vec<rspotify::model::PlaylistItem>) -> MockSource {
+        MockSource {
+            playlists: HashMap::from([(id.to_string(), items)]),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn fetch_playlist_items_collects_every_page() {
+        let items = (0..120)
+            .map(|i| {
+                mock::playlist_item(
+                    mock::track_json(&format!("track{}", i)),
+                    Some("2021-01-01T00:00:00Z"),
+                    Some("alice"),
+                )
+            })
+            .collect();
+        let source = source_with_playlist(MAIN_PLAYLIST_ID, items);
+        let tracks = fetch_playlist_items(&source, &PlaylistId::from_id(MAIN_PLAYLIST_ID).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(tracks.len(), 120);
+        assert_eq!(tracks[0].track.id.id(), "track0");
+        assert_eq!(tracks[119].track.id.id(), "track119");
+        assert_eq!(tracks[0].added_by.as_deref(), Some("alice"));
+        assert!(tracks[0].added_at.is_some());
+    }
+
+    #[tokio::test]
+    async fn populate_stores_playlist_and_album_tracks_with_features() {
+        let db = temporary_db();
+        let mut source = source_with_playlist(
+            MAIN_PLAYLIST_ID,
+            vec![mock::playlist_item(
+                mock::track_json("playlisttrack"),
+                Some("2021-01-01T00:00:00Z"),
+                Some("alice"),
+            )],
+        );
+        source.saved_albums = vec![mock::saved_album("album1")];
+        source.albums = HashMap::from([(
+            "album1".to_string(),
+            vec![
+                mock::simplified_track("albumtrack1"),
+                mock::simplified_track("albumtrack2"),
+            ],
+        )]);
+        source.features = ["playlisttrack", "albumtrack1", "albumtrack2"]
+            .iter()
+            .map(|id| (id.to_string(), mock::audio_features(id)))
+            .collect();
+        populate_track_details(&source, db.clone()).await.unwrap();
+        populate_track_features(&source, db.clone()).await.unwrap();
+
+        let details = db.open_tree("track_details").unwrap();
+        for id in ["playlisttrack", "albumtrack1", "albumtrack2"] {
+            assert!(details.contains_key(id).unwrap(), "{} not stored", id);
+        }
+        assert_eq!(details.len(), 3);
+        assert_eq!(
+            db.open_tree("track_added_by")
+                .unwrap()
+                .get("playlisttrack")
+                .unwrap()
+                .as_deref(),
+            Some(&b"alice"[..])
+        );
+        assert!(db
+            .open_tree("track_extras")
+            .unwrap()
+            .contains_key("albumtrack1")
+            .unwrap());
+        assert_eq!(db.open_tree("track_features").unwrap().len(), 3);
+        // a finished run leaves no progress markers behind
+        assert!(db
+            .open_tree("meta")
+            .unwrap()
+            .scan_prefix("populate/")
+            .next()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn write_playlist_replaces_a_mostly_different_playlist() {
+        let source = source_with_playlist(
+            "target",
+            vec![
+                mock::playlist_item(mock::track_json("old1"), None, None),
+                mock::playlist_item(mock::track_json("old2"), None, None),
+            ],
+        );
+        let new = TrackId::from_id("new1").unwrap();
+        write_playlist(
+            &source,
+            &PlaylistId::from_id("target").unwrap(),
+            [&new as &dyn PlayableId],
+            WriteMode::Replace,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            source.writes(),
+            vec![
+                Write::Replace {
+                    playlist: "target".to_string(),
+                    tracks: vec![],
+                },
+                Write::Add {
+                    playlist: "target".to_string(),
+                    tracks: vec!["spotify:track:new1".to_string()],
+                    position: Some(0),
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn write_playlist_appends_missing_tracks_at_the_end() {
+        let mut local = mock::track_json("local1");
+        local["is_local"] = true.into();
+        let source = source_with_playlist(
+            "target",
+            vec![
+                mock::playlist_item(mock::track_json("old1"), None, None),
+                mock::playlist_item(local, None, None),
+            ],
+        );
+        let tracks = [
+            TrackId::from_id("old1").unwrap(),
+            TrackId::from_id("new1").unwrap(),
+        ];
+        write_playlist(
+            &source,
+            &PlaylistId::from_id("target").unwrap(),
+            tracks.iter().map(|track| track as &dyn PlayableId),
+            WriteMode::Append,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            source.writes(),
+            vec![Write::Add {
+                playlist: "target".to_string(),
+                tracks: vec!["spotify:track:new1".to_string()],
+                position: None,
+            }]
+        );
+    }
+
+    #[tokio::test]
+    async fn write_playlist_leaves_an_up_to_date_playlist_alone() {
+        let source = source_with_playlist(
+            "target",
+            vec![
+                mock::playlist_item(mock::track_json("track1"), None, None),
+                mock::playlist_item(mock::track_json("track2"), None, None),
+            ],
+        );
+        let tracks = [
+            TrackId::from_id("track1").unwrap(),
+            TrackId::from_id("track2").unwrap(),
+        ];
+        for mode in [WriteMode::Replace, WriteMode::Append] {
+            write_playlist(
+                &source,
+                &PlaylistId::from_id("target").unwrap(),
+                tracks.iter().map(|track| track as &dyn PlayableId),
+                mode,
+            )
+            .await
+            .unwrap();
+        }
+        assert_eq!(source.writes(), vec![]);
+    }
+
+    #[tokio::test]
+    async fn fetch_features_requests_batches_of_limited_size() {
+        let db = temporary_db();
+        let source = MockSource::default();
+        let track_ids = (0..250)
+            .map(|i| TrackId::from_id(&format!("track{}", i)).unwrap())
+            .collect_vec();
+        let stored = fetch_features(&source, &db, &track_ids).await.unwrap();
+        assert_eq!(stored, 250);
+        assert_eq!(db.open_tree("track_features").unwrap().len(), 250);
+        let mut requests = source.features_requests.lock().unwrap().clone();
+        requests.sort_unstable();
+        assert_eq!(requests, vec![50, FEATURES_BATCH_SIZE, FEATURES_BATCH_SIZE]);
+    }
+
+    #[test]
+    fn simplify_track_drops_local_tracks() {
+        let mut local = mock::full_track("local1");
+        local.is_local = true;
+        assert!(simplify_track(local).is_none());
+        let simplified = simplify_track(mock::full_track("track1")).unwrap();
+        assert_eq!(simplified.id.unwrap().id(), "track1");
+        assert_eq!(simplified.name, "track track1");
+    }
+
+    #[tokio::test]
+    async fn fetch_playlist_items_drops_local_and_unavailable_tracks() {
+        let mut local = mock::track_json("local1");
+        local["is_local"] = true.into();
+        let mut unavailable = mock::track_json("gone1");
+        unavailable["is_playable"] = false.into();
+        let mut playable = mock::track_json("track2");
+        playable["is_playable"] = true.into();
+        let source = source_with_playlist(
+            "target",
+            vec![
+                mock::playlist_item(mock::track_json("track1"), None, None),
+                mock::playlist_item(local, None, None),
+                mock::playlist_item(unavailable, None, None),
+                mock::playlist_item(playable, None, None),
+            ],
+        );
+        let tracks = fetch_playlist_items(&source, &PlaylistId::from_id("target").unwrap())
+            .await
+            .unwrap();
+        assert_eq!(
+            tracks.iter().map(|track| track.track.id.id()).collect_vec(),
+            vec!["track1", "track2"]
+        );
+    }
+
+    fn playlist_track(id: &str, added_at: Option<&str>, added_by: Option<&str>) -> PlaylistTrack {
+        PlaylistTrack {
+            track: mock::full_track(id),
+            added_at: added_at.map(|added_at| added_at.parse().unwrap()),
+            added_by: added_by.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn rolling_selection_puts_latest_additions_first() {
+        let db = temporary_db();
+        let main_playlist = vec![
+            playlist_track("january", Some("2021-01-01T00:00:00Z"), Some("alice")),
+            playlist_track("unknown", None, Some("alice")),
+            playlist_track("march", Some("2021-03-01T00:00:00Z"), Some("alice")),
+            playlist_track("february", Some("2021-02-01T00:00:00Z"), Some("bob")),
+        ];
+        let selected = |settings: &RollingSettings| {
+            rolling_selection(&main_playlist, &db, settings)
+                .unwrap()
+                .into_iter()
+                .map(|track| track.id.id().to_string())
+                .collect_vec()
+        };
+        assert_eq!(
+            selected(&RollingSettings::default()),
+            vec!["march", "february", "january", "unknown"]
+        );
+        let settings = RollingSettings {
+            contributors: HashSet::from(["bob".to_string()]),
+            ..Default::default()
+        };
+        assert_eq!(
+            selected(&settings),
+            vec!["february", "march", "january", "unknown"]
+        );
+    }
+}
@@ -0,0 +1,62 @@
+use color_eyre::{eyre::eyre, Result};
+use sled::Db;
+use tracing::{info, instrument};
+
+/// Version of the database layout this build reads and writes.
+pub(crate) const SCHEMA_VERSION: u32 = 2;
+
+/// Brings the database up to [`SCHEMA_VERSION`], recording the version in the `meta` tree.
+#[instrument(skip(db))]
+pub(crate) fn migrate(db: &Db) -> Result<()> {
+    let meta_tree = db.open_tree("meta")?;
+    let detected = match meta_tree.get("schema_version")? {
+        Some(bytes) => u32::from_be_bytes(
+            bytes
+                .as_ref()
+                .try_into()
+                .map_err(|_| eyre!("malformed schema version"))?,
+        ),
+        // a database without track details has never been populated
+        None if !db
+            .tree_names()
+            .iter()
+            .any(|name| name.as_ref() == "track_details".as_bytes()) =>
+        {
+            SCHEMA_VERSION
+        }
+        None => 0,
+    };
+    info!(
+        detected,
+        target = SCHEMA_VERSION,
+        "checking database schema"
+    );
+    if detected > SCHEMA_VERSION {
+        return Err(eyre!(
+            "database schema version {} is newer than supported version {}",
+            detected,
+            SCHEMA_VERSION
+        ));
+    }
+    for version in detected..SCHEMA_VERSION {
+        info!(from = version, to = version + 1, "migrating database");
+        match version {
+            // version 0 predates versioning and shares the layout of version 1
+            0 => {}
+            // version 2 added the `key_unknown` column, so models fitted without it are dropped
+            1 => {
+                for name in db.tree_names() {
+                    if name.starts_with(b"models/") {
+                        let models_tree = db.open_tree(name)?;
+                        models_tree.remove("model")?;
+                        models_tree.remove("metadata")?;
+                    }
+                }
+            }
+            _ => unreachable!("no migration from schema version {}", version),
+        }
+        meta_tree.insert("schema_version", &(version + 1).to_be_bytes())?;
+    }
+    meta_tree.insert("schema_version", &SCHEMA_VERSION.to_be_bytes())?;
+    Ok(())
+}
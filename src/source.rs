@@ -0,0 +1,855 @@
+use std::{
+    future::Future,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use futures_util::{future::BoxFuture, FutureExt};
+use rspotify::{
+    clients::{BaseClient, OAuthClient},
+    model::{
+        AlbumId, ArtistId, AudioFeatures, Page, PlayableId, PlaylistId, PlaylistItem,
+        RecommendationsAttribute, SavedAlbum, SavedTrack, SimplifiedTrack, TrackId,
+    },
+    ClientResult,
+};
+
+use tracing::{error, warn};
+
+use crate::{
+    client_error::{classify, ErrorKind},
+    governor::Governor,
+    kickstart::{self, Client},
+};
+
+/// Items requested per page from paginated endpoints, the most all of them accept.
+const PAGE_SIZE: u32 = 50;
+
+/// Requests the pages of a paginated endpoint one after another, collecting their items.
+async fn collect_pages<T, F>(mut page: impl FnMut(u32) -> F) -> ClientResult<Vec<T>>
+where
+    F: Future<Output = ClientResult<Page<T>>>,
+{
+    let mut items = vec![];
+    loop {
+        let Page {
+            items: page_items,
+            next,
+            ..
+        } = page(items.len() as u32).await?;
+        let last = next.is_none() || page_items.is_empty();
+        items.extend(page_items);
+        if last {
+            return Ok(items);
+        }
+    }
+}
+
+/// The Spotify calls made while populating the database and updating playlists.
+///
+/// Paginated endpoints are requested a page at a time, each page being a request of its own,
+/// and collected in full by the provided methods.
+pub(crate) trait SpotifySource: Send + Sync {
+    fn playlist_snapshot_id<'a>(
+        &'a self,
+        id: &'a PlaylistId,
+    ) -> BoxFuture<'a, ClientResult<String>>;
+
+    fn playlist_items_page<'a>(
+        &'a self,
+        id: &'a PlaylistId,
+        offset: u32,
+    ) -> BoxFuture<'a, ClientResult<Page<PlaylistItem>>>;
+
+    fn album_tracks_page<'a>(
+        &'a self,
+        id: &'a AlbumId,
+        offset: u32,
+    ) -> BoxFuture<'a, ClientResult<Page<SimplifiedTrack>>>;
+
+    fn saved_albums_page(&self, offset: u32) -> BoxFuture<'_, ClientResult<Page<SavedAlbum>>>;
+
+    fn saved_tracks_page(&self, offset: u32) -> BoxFuture<'_, ClientResult<Page<SavedTrack>>>;
+
+    fn playlist_items<'a>(
+        &'a self,
+        id: &'a PlaylistId,
+    ) -> BoxFuture<'a, ClientResult<Vec<PlaylistItem>>> {
+        collect_pages(move |offset| self.playlist_items_page(id, offset)).boxed()
+    }
+
+    fn album_tracks<'a>(
+        &'a self,
+        id: &'a AlbumId,
+    ) -> BoxFuture<'a, ClientResult<Vec<SimplifiedTrack>>> {
+        collect_pages(move |offset| self.album_tracks_page(id, offset)).boxed()
+    }
+
+    fn saved_albums(&self) -> BoxFuture<'_, ClientResult<Vec<SavedAlbum>>> {
+        collect_pages(move |offset| self.saved_albums_page(offset)).boxed()
+    }
+
+    fn saved_tracks(&self) -> BoxFuture<'_, ClientResult<Vec<SavedTrack>>> {
+        collect_pages(move |offset| self.saved_tracks_page(offset)).boxed()
+    }
+
+    fn tracks_features<'a>(
+        &'a self,
+        ids: &'a [TrackId],
+    ) -> BoxFuture<'a, ClientResult<Option<Vec<AudioFeatures>>>>;
+
+    fn recommendations<'a>(
+        &'a self,
+        seed_tracks: Vec<&'a TrackId>,
+        limit: u32,
+    ) -> BoxFuture<'a, ClientResult<Vec<SimplifiedTrack>>>;
+
+    fn playlist_add_items<'a>(
+        &'a self,
+        id: &'a PlaylistId,
+        items: Vec<&'a dyn PlayableId>,
+        position: Option<u32>,
+    ) -> BoxFuture<'a, ClientResult<()>>;
+
+    fn playlist_remove_all_occurrences_of_items<'a>(
+        &'a self,
+        id: &'a PlaylistId,
+        items: Vec<&'a dyn PlayableId>,
+    ) -> BoxFuture<'a, ClientResult<()>>;
+
+    fn playlist_replace_items<'a>(
+        &'a self,
+        id: &'a PlaylistId,
+        items: Vec<&'a dyn PlayableId>,
+    ) -> BoxFuture<'a, ClientResult<()>>;
+
+    /// Creates a private playlist owned by the current user.
+    fn create_playlist<'a>(&'a self, name: &'a str) -> BoxFuture<'a, ClientResult<PlaylistId>>;
+}
+
+impl SpotifySource for Client {
+    fn playlist_snapshot_id<'a>(
+        &'a self,
+        id: &'a PlaylistId,
+    ) -> BoxFuture<'a, ClientResult<String>> {
+        async move { Ok(self.playlist(id, None, None).await?.snapshot_id) }.boxed()
+    }
+
+    fn playlist_items_page<'a>(
+        &'a self,
+        id: &'a PlaylistId,
+        offset: u32,
+    ) -> BoxFuture<'a, ClientResult<Page<PlaylistItem>>> {
+        self.playlist_items_manual(id, None, None, Some(PAGE_SIZE), Some(offset))
+            .boxed()
+    }
+
+    fn album_tracks_page<'a>(
+        &'a self,
+        id: &'a AlbumId,
+        offset: u32,
+    ) -> BoxFuture<'a, ClientResult<Page<SimplifiedTrack>>> {
+        self.album_track_manual(id, Some(PAGE_SIZE), Some(offset))
+            .boxed()
+    }
+
+    fn saved_albums_page(&self, offset: u32) -> BoxFuture<'_, ClientResult<Page<SavedAlbum>>> {
+        self.current_user_saved_albums_manual(None, Some(PAGE_SIZE), Some(offset))
+            .boxed()
+    }
+
+    fn saved_tracks_page(&self, offset: u32) -> BoxFuture<'_, ClientResult<Page<SavedTrack>>> {
+        self.current_user_saved_tracks_manual(None, Some(PAGE_SIZE), Some(offset))
+            .boxed()
+    }
+
+    fn tracks_features<'a>(
+        &'a self,
+        ids: &'a [TrackId],
+    ) -> BoxFuture<'a, ClientResult<Option<Vec<AudioFeatures>>>> {
+        BaseClient::tracks_features(self, ids).boxed()
+    }
+
+    fn recommendations<'a>(
+        &'a self,
+        seed_tracks: Vec<&'a TrackId>,
+        limit: u32,
+    ) -> BoxFuture<'a, ClientResult<Vec<SimplifiedTrack>>> {
+        async move {
+            let recommendations = BaseClient::recommendations(
+                self,
+                Vec::<&RecommendationsAttribute>::new(),
+                None::<Vec<&ArtistId>>,
+                None::<Vec<&str>>,
+                Some(seed_tracks),
+                None,
+                Some(limit),
+            )
+            .await?;
+            Ok(recommendations.tracks)
+        }
+        .boxed()
+    }
+
+    fn playlist_add_items<'a>(
+        &'a self,
+        id: &'a PlaylistId,
+        items: Vec<&'a dyn PlayableId>,
+        position: Option<u32>,
+    ) -> BoxFuture<'a, ClientResult<()>> {
+        async move {
+            OAuthClient::playlist_add_items(self, id, items, position).await?;
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn playlist_remove_all_occurrences_of_items<'a>(
+        &'a self,
+        id: &'a PlaylistId,
+        items: Vec<&'a dyn PlayableId>,
+    ) -> BoxFuture<'a, ClientResult<()>> {
+        async move {
+            OAuthClient::playlist_remove_all_occurrences_of_items(self, id, items, None).await?;
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn playlist_replace_items<'a>(
+        &'a self,
+        id: &'a PlaylistId,
+        items: Vec<&'a dyn PlayableId>,
+    ) -> BoxFuture<'a, ClientResult<()>> {
+        OAuthClient::playlist_replace_items(self, id, items).boxed()
+    }
+
+    fn create_playlist<'a>(&'a self, name: &'a str) -> BoxFuture<'a, ClientResult<PlaylistId>> {
+        async move {
+            let user = self.current_user().await?;
+            let playlist = self
+                .user_playlist_create(&user.id, name, Some(false), None, None)
+                .await?;
+            Ok(playlist.id)
+        }
+        .boxed()
+    }
+}
+
+/// A source whose every request, including each page of a paginated one, first waits for the
+/// [`Governor`].
+pub(crate) struct Governed<S> {
+    inner: S,
+    governor: Governor,
+}
+
+impl<S> Governed<S> {
+    pub(crate) fn new(inner: S, governor: Governor) -> Self {
+        Self { inner, governor }
+    }
+}
+
+impl<S: SpotifySource> SpotifySource for Governed<S> {
+    fn playlist_snapshot_id<'a>(
+        &'a self,
+        id: &'a PlaylistId,
+    ) -> BoxFuture<'a, ClientResult<String>> {
+        async move {
+            let _permit = self.governor.acquire().await;
+            self.inner.playlist_snapshot_id(id).await
+        }
+        .boxed()
+    }
+
+    fn playlist_items_page<'a>(
+        &'a self,
+        id: &'a PlaylistId,
+        offset: u32,
+    ) -> BoxFuture<'a, ClientResult<Page<PlaylistItem>>> {
+        async move {
+            let _permit = self.governor.acquire().await;
+            self.inner.playlist_items_page(id, offset).await
+        }
+        .boxed()
+    }
+
+    fn album_tracks_page<'a>(
+        &'a self,
+        id: &'a AlbumId,
+        offset: u32,
+    ) -> BoxFuture<'a, ClientResult<Page<SimplifiedTrack>>> {
+        async move {
+            let _permit = self.governor.acquire().await;
+            self.inner.album_tracks_page(id, offset).await
+        }
+        .boxed()
+    }
+
+    fn saved_albums_page(&self, offset: u32) -> BoxFuture<'_, ClientResult<Page<SavedAlbum>>> {
+        async move {
+            let _permit = self.governor.acquire().await;
+            self.inner.saved_albums_page(offset).await
+        }
+        .boxed()
+    }
+
+    fn saved_tracks_page(&self, offset: u32) -> BoxFuture<'_, ClientResult<Page<SavedTrack>>> {
+        async move {
+            let _permit = self.governor.acquire().await;
+            self.inner.saved_tracks_page(offset).await
+        }
+        .boxed()
+    }
+
+    fn tracks_features<'a>(
+        &'a self,
+        ids: &'a [TrackId],
+    ) -> BoxFuture<'a, ClientResult<Option<Vec<AudioFeatures>>>> {
+        async move {
+            let _permit = self.governor.acquire().await;
+            self.inner.tracks_features(ids).await
+        }
+        .boxed()
+    }
+
+    fn recommendations<'a>(
+        &'a self,
+        seed_tracks: Vec<&'a TrackId>,
+        limit: u32,
+    ) -> BoxFuture<'a, ClientResult<Vec<SimplifiedTrack>>> {
+        async move {
+            let _permit = self.governor.acquire().await;
+            self.inner.recommendations(seed_tracks, limit).await
+        }
+        .boxed()
+    }
+
+    fn playlist_add_items<'a>(
+        &'a self,
+        id: &'a PlaylistId,
+        items: Vec<&'a dyn PlayableId>,
+        position: Option<u32>,
+    ) -> BoxFuture<'a, ClientResult<()>> {
+        async move {
+            let _permit = self.governor.acquire().await;
+            self.inner.playlist_add_items(id, items, position).await
+        }
+        .boxed()
+    }
+
+    fn playlist_remove_all_occurrences_of_items<'a>(
+        &'a self,
+        id: &'a PlaylistId,
+        items: Vec<&'a dyn PlayableId>,
+    ) -> BoxFuture<'a, ClientResult<()>> {
+        async move {
+            let _permit = self.governor.acquire().await;
+            self.inner
+                .playlist_remove_all_occurrences_of_items(id, items)
+                .await
+        }
+        .boxed()
+    }
+
+    fn playlist_replace_items<'a>(
+        &'a self,
+        id: &'a PlaylistId,
+        items: Vec<&'a dyn PlayableId>,
+    ) -> BoxFuture<'a, ClientResult<()>> {
+        async move {
+            let _permit = self.governor.acquire().await;
+            self.inner.playlist_replace_items(id, items).await
+        }
+        .boxed()
+    }
+
+    fn create_playlist<'a>(&'a self, name: &'a str) -> BoxFuture<'a, ClientResult<PlaylistId>> {
+        async move {
+            let _permit = self.governor.acquire().await;
+            self.inner.create_playlist(name).await
+        }
+        .boxed()
+    }
+}
+
+/// A source that, the first time Spotify rejects the session, runs the interactive login again
+/// and retries the rejected request, so that a long CLI run survives a revoked token.
+pub(crate) struct Reauthorizing<S> {
+    inner: S,
+    /// The client whose token the login replaces, shared with `inner`.
+    client: Client,
+    reauthorized: AtomicBool,
+}
+
+impl<S> Reauthorizing<S> {
+    pub(crate) fn new(inner: S, client: Client) -> Self {
+        Self {
+            inner,
+            client,
+            reauthorized: AtomicBool::new(false),
+        }
+    }
+
+    async fn retrying<T, F>(&self, request: impl Fn() -> F) -> ClientResult<T>
+    where
+        F: Future<Output = ClientResult<T>>,
+    {
+        match request().await {
+            Err(error)
+                if classify(&error) == ErrorKind::Unauthorized
+                    && !self.reauthorized.swap(true, Ordering::SeqCst) =>
+            {
+                warn!(%error, "Spotify session was rejected, logging in again");
+                if let Err(login_error) = kickstart::login(&self.client).await {
+                    error!(%login_error, "logging in again failed");
+                    return Err(error);
+                }
+                request().await
+            }
+            result => result,
+        }
+    }
+}
+
+impl<S: SpotifySource> SpotifySource for Reauthorizing<S> {
+    fn playlist_snapshot_id<'a>(
+        &'a self,
+        id: &'a PlaylistId,
+    ) -> BoxFuture<'a, ClientResult<String>> {
+        self.retrying(move || self.inner.playlist_snapshot_id(id))
+            .boxed()
+    }
+
+    fn playlist_items_page<'a>(
+        &'a self,
+        id: &'a PlaylistId,
+        offset: u32,
+    ) -> BoxFuture<'a, ClientResult<Page<PlaylistItem>>> {
+        self.retrying(move || self.inner.playlist_items_page(id, offset))
+            .boxed()
+    }
+
+    fn album_tracks_page<'a>(
+        &'a self,
+        id: &'a AlbumId,
+        offset: u32,
+    ) -> BoxFuture<'a, ClientResult<Page<SimplifiedTrack>>> {
+        self.retrying(move || self.inner.album_tracks_page(id, offset))
+            .boxed()
+    }
+
+    fn saved_albums_page(&self, offset: u32) -> BoxFuture<'_, ClientResult<Page<SavedAlbum>>> {
+        self.retrying(move || self.inner.saved_albums_page(offset))
+            .boxed()
+    }
+
+    fn saved_tracks_page(&self, offset: u32) -> BoxFuture<'_, ClientResult<Page<SavedTrack>>> {
+        self.retrying(move || self.inner.saved_tracks_page(offset))
+            .boxed()
+    }
+
+    fn tracks_features<'a>(
+        &'a self,
+        ids: &'a [TrackId],
+    ) -> BoxFuture<'a, ClientResult<Option<Vec<AudioFeatures>>>> {
+        self.retrying(move || self.inner.tracks_features(ids))
+            .boxed()
+    }
+
+    fn recommendations<'a>(
+        &'a self,
+        seed_tracks: Vec<&'a TrackId>,
+        limit: u32,
+    ) -> BoxFuture<'a, ClientResult<Vec<SimplifiedTrack>>> {
+        self.retrying(move || self.inner.recommendations(seed_tracks.clone(), limit))
+            .boxed()
+    }
+
+    fn playlist_add_items<'a>(
+        &'a self,
+        id: &'a PlaylistId,
+        items: Vec<&'a dyn PlayableId>,
+        position: Option<u32>,
+    ) -> BoxFuture<'a, ClientResult<()>> {
+        self.retrying(move || self.inner.playlist_add_items(id, items.clone(), position))
+            .boxed()
+    }
+
+    fn playlist_remove_all_occurrences_of_items<'a>(
+        &'a self,
+        id: &'a PlaylistId,
+        items: Vec<&'a dyn PlayableId>,
+    ) -> BoxFuture<'a, ClientResult<()>> {
+        self.retrying(move || {
+            self.inner
+                .playlist_remove_all_occurrences_of_items(id, items.clone())
+        })
+        .boxed()
+    }
+
+    fn playlist_replace_items<'a>(
+        &'a self,
+        id: &'a PlaylistId,
+        items: Vec<&'a dyn PlayableId>,
+    ) -> BoxFuture<'a, ClientResult<()>> {
+        self.retrying(move || self.inner.playlist_replace_items(id, items.clone()))
+            .boxed()
+    }
+
+    fn create_playlist<'a>(&'a self, name: &'a str) -> BoxFuture<'a, ClientResult<PlaylistId>> {
+        self.retrying(move || self.inner.create_playlist(name))
+            .boxed()
+    }
+}
+
+/// A source serving canned data and recording the playlist writes made to it.
+#[cfg(test)]
+pub(crate) mod mock {
+    use std::{collections::HashMap, sync::Mutex};
+
+    use futures_util::{future::BoxFuture, FutureExt};
+    use rspotify::{
+        model::{
+            AlbumId, AudioFeatures, FullTrack, Id, Page, PlayableId, PlaylistId, PlaylistItem,
+            SavedAlbum, SavedTrack, SimplifiedTrack, TrackId,
+        },
+        ClientResult,
+    };
+    use serde_json::{json, Value};
+
+    use super::{SpotifySource, PAGE_SIZE};
+
+    /// A playlist change received by [`MockSource`], with tracks given by URI.
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub(crate) enum Write {
+        Add {
+            playlist: String,
+            tracks: Vec<String>,
+            position: Option<u32>,
+        },
+        RemoveAll {
+            playlist: String,
+            tracks: Vec<String>,
+        },
+        Replace {
+            playlist: String,
+            tracks: Vec<String>,
+        },
+        Create {
+            name: String,
+        },
+    }
+
+    #[derive(Default)]
+    pub(crate) struct MockSource {
+        /// Items of each playlist, by playlist id.
+        pub(crate) playlists: HashMap<String, Vec<PlaylistItem>>,
+        /// Tracks of each album, by album id.
+        pub(crate) albums: HashMap<String, Vec<SimplifiedTrack>>,
+        pub(crate) saved_albums: Vec<SavedAlbum>,
+        pub(crate) saved_tracks: Vec<SavedTrack>,
+        /// Audio features by track id, served as null for tracks without any.
+        pub(crate) features: HashMap<String, AudioFeatures>,
+        pub(crate) recommendations: Vec<SimplifiedTrack>,
+        /// Number of tracks in each audio features request received so far.
+        pub(crate) features_requests: Mutex<Vec<usize>>,
+        pub(crate) writes: Mutex<Vec<Write>>,
+    }
+
+    impl MockSource {
+        /// The writes received so far, oldest first.
+        pub(crate) fn writes(&self) -> Vec<Write> {
+            self.writes.lock().unwrap().clone()
+        }
+
+        fn record(&self, write: Write) {
+            self.writes.lock().unwrap().push(write);
+        }
+    }
+
+    fn uris(items: &[&dyn PlayableId]) -> Vec<String> {
+        items.iter().map(|item| item.uri()).collect()
+    }
+
+    /// The page of `items` starting at `offset`, as Spotify would paginate them.
+    fn page<T: Clone>(items: &[T], offset: u32) -> Page<T> {
+        let start = (offset as usize).min(items.len());
+        let end = (start + PAGE_SIZE as usize).min(items.len());
+        Page {
+            href: String::new(),
+            items: items[start..end].to_vec(),
+            limit: PAGE_SIZE,
+            next: (end < items.len()).then(|| format!("offset={}", end)),
+            offset,
+            previous: None,
+            total: items.len() as u32,
+        }
+    }
+
+    impl SpotifySource for MockSource {
+        fn playlist_snapshot_id<'a>(
+            &'a self,
+            id: &'a PlaylistId,
+        ) -> BoxFuture<'a, ClientResult<String>> {
+            let snapshot_id = format!(
+                "{}:{}",
+                id.id(),
+                self.playlists.get(id.id()).map_or(0, Vec::len)
+            );
+            async move { Ok(snapshot_id) }.boxed()
+        }
+
+        fn playlist_items_page<'a>(
+            &'a self,
+            id: &'a PlaylistId,
+            offset: u32,
+        ) -> BoxFuture<'a, ClientResult<Page<PlaylistItem>>> {
+            let items = self.playlists.get(id.id()).map_or(&[][..], Vec::as_slice);
+            let page = page(items, offset);
+            async move { Ok(page) }.boxed()
+        }
+
+        fn album_tracks_page<'a>(
+            &'a self,
+            id: &'a AlbumId,
+            offset: u32,
+        ) -> BoxFuture<'a, ClientResult<Page<SimplifiedTrack>>> {
+            let tracks = self.albums.get(id.id()).map_or(&[][..], Vec::as_slice);
+            let page = page(tracks, offset);
+            async move { Ok(page) }.boxed()
+        }
+
+        fn saved_albums_page(&self, offset: u32) -> BoxFuture<'_, ClientResult<Page<SavedAlbum>>> {
+            let page = page(&self.saved_albums, offset);
+            async move { Ok(page) }.boxed()
+        }
+
+        fn saved_tracks_page(&self, offset: u32) -> BoxFuture<'_, ClientResult<Page<SavedTrack>>> {
+            let page = page(&self.saved_tracks, offset);
+            async move { Ok(page) }.boxed()
+        }
+
+        fn tracks_features<'a>(
+            &'a self,
+            ids: &'a [TrackId],
+        ) -> BoxFuture<'a, ClientResult<Option<Vec<AudioFeatures>>>> {
+            self.features_requests.lock().unwrap().push(ids.len());
+            let features = ids
+                .iter()
+                .map(|id| self.features.get(id.id()).cloned().flatten())
+                .collect();
+            async move { Ok(Some(features)) }.boxed()
+        }
+
+        fn recommendations<'a>(
+            &'a self,
+            _seed_tracks: Vec<&'a TrackId>,
+            limit: u32,
+        ) -> BoxFuture<'a, ClientResult<Vec<SimplifiedTrack>>> {
+            let recommendations = self
+                .recommendations
+                .iter()
+                .take(limit as usize)
+                .cloned()
+                .collect();
+            async move { Ok(recommendations) }.boxed()
+        }
+
+        fn playlist_add_items<'a>(
+            &'a self,
+            id: &'a PlaylistId,
+            items: Vec<&'a dyn PlayableId>,
+            position: Option<u32>,
+        ) -> BoxFuture<'a, ClientResult<()>> {
+            self.record(Write::Add {
+                playlist: id.id().to_string(),
+                tracks: uris(&items),
+                position,
+            });
+            async { Ok(()) }.boxed()
+        }
+
+        fn playlist_remove_all_occurrences_of_items<'a>(
+            &'a self,
+            id: &'a PlaylistId,
+            items: Vec<&'a dyn PlayableId>,
+        ) -> BoxFuture<'a, ClientResult<()>> {
+            self.record(Write::RemoveAll {
+                playlist: id.id().to_string(),
+                tracks: uris(&items),
+            });
+            async { Ok(()) }.boxed()
+        }
+
+        fn playlist_replace_items<'a>(
+            &'a self,
+            id: &'a PlaylistId,
+            items: Vec<&'a dyn PlayableId>,
+        ) -> BoxFuture<'a, ClientResult<()>> {
+            self.record(Write::Replace {
+                playlist: id.id().to_string(),
+                tracks: uris(&items),
+            });
+            async { Ok(()) }.boxed()
+        }
+
+        fn create_playlist<'a>(&'a self, name: &'a str) -> BoxFuture<'a, ClientResult<PlaylistId>> {
+            self.record(Write::Create {
+                name: name.to_string(),
+            });
+            let created = self
+                .writes()
+                .iter()
+                .filter(|write| matches!(write, Write::Create { .. }))
+                .count();
+            let id = PlaylistId::from_id(&format!("created{}", created)).unwrap();
+            async move { Ok(id) }.boxed()
+        }
+    }
+
+    /// A catalog track as the Spotify API returns it.
+    pub(crate) fn track_json(id: &str) -> Value {
+        json!({
+            "album": {
+                "album_type": "album",
+                "artists": [],
+                "available_markets": [],
+                "external_urls": {},
+                "href": format!("https://api.spotify.com/v1/albums/album{}", id),
+                "id": format!("album{}", id),
+                "images": [],
+                "name": format!("album of {}", id),
+                "release_date": "2020-01-01",
+                "release_date_precision": "day",
+                "total_tracks": 1,
+                "type": "album",
+                "uri": format!("spotify:album:album{}", id),
+            },
+            "artists": [{
+                "external_urls": {},
+                "href": "https://api.spotify.com/v1/artists/artist",
+                "id": "artist",
+                "name": "artist",
+                "type": "artist",
+                "uri": "spotify:artist:artist",
+            }],
+            "available_markets": [],
+            "disc_number": 1,
+            "duration_ms": 180000,
+            "explicit": false,
+            "external_ids": {},
+            "external_urls": {},
+            "href": format!("https://api.spotify.com/v1/tracks/{}", id),
+            "id": id,
+            "is_local": false,
+            "name": format!("track {}", id),
+            "popularity": 50,
+            "preview_url": null,
+            "track_number": 1,
+            "type": "track",
+            "uri": format!("spotify:track:{}", id),
+        })
+    }
+
+    /// A catalog track, as [`track_json`] describes it.
+    pub(crate) fn full_track(id: &str) -> FullTrack {
+        serde_json::from_value(track_json(id)).unwrap()
+    }
+
+    /// A track as album endpoints return it, without album and popularity.
+    pub(crate) fn simplified_track(id: &str) -> SimplifiedTrack {
+        let mut track = track_json(id);
+        let object = track.as_object_mut().unwrap();
+        for field in ["album", "external_ids", "popularity"] {
+            object.remove(field);
+        }
+        serde_json::from_value(track).unwrap()
+    }
+
+    /// A playlist entry holding the given track JSON.
+    pub(crate) fn playlist_item(
+        track: Value,
+        added_at: Option<&str>,
+        added_by: Option<&str>,
+    ) -> PlaylistItem {
+        serde_json::from_value(json!({
+            "added_at": added_at,
+            "added_by": added_by.map(|user| json!({
+                "display_name": null,
+                "external_urls": {},
+                "followers": null,
+                "href": format!("https://api.spotify.com/v1/users/{}", user),
+                "id": user,
+                "images": [],
+                "type": "user",
+                "uri": format!("spotify:user:{}", user),
+            })),
+            "is_local": track["is_local"],
+            "primary_color": null,
+            "track": track,
+            "video_thumbnail": { "url": null },
+        }))
+        .unwrap()
+    }
+
+    /// A library album, whose tracks are served separately through [`MockSource::albums`].
+    pub(crate) fn saved_album(id: &str) -> SavedAlbum {
+        serde_json::from_value(json!({
+            "added_at": "2021-01-01T00:00:00Z",
+            "album": {
+                "album_type": "album",
+                "artists": [],
+                "available_markets": [],
+                "copyrights": [],
+                "external_ids": {},
+                "external_urls": {},
+                "genres": [],
+                "href": format!("https://api.spotify.com/v1/albums/{}", id),
+                "id": id,
+                "images": [],
+                "label": "label",
+                "name": format!("album {}", id),
+                "popularity": 40,
+                "release_date": "2019-06-01",
+                "release_date_precision": "day",
+                "total_tracks": 0,
+                "tracks": {
+                    "href": "",
+                    "items": [],
+                    "limit": 50,
+                    "next": null,
+                    "offset": 0,
+                    "previous": null,
+                    "total": 0,
+                },
+                "type": "album",
+                "uri": format!("spotify:album:{}", id),
+            },
+        }))
+        .unwrap()
+    }
+
+    /// Audio features as the Spotify API returns them.
+    pub(crate) fn audio_features(id: &str) -> AudioFeatures {
+        serde_json::from_value(json!({
+            "acousticness": 0.1,
+            "analysis_url": format!("https://api.spotify.com/v1/audio-analysis/{}", id),
+            "danceability": 0.6,
+            "duration_ms": 180000,
+            "energy": 0.7,
+            "id": id,
+            "instrumentalness": 0.0,
+            "key": 5,
+            "liveness": 0.1,
+            "loudness": -6.0,
+            "mode": 1,
+            "speechiness": 0.05,
+            "tempo": 120.0,
+            "time_signature": 4,
+            "track_href": format!("https://api.spotify.com/v1/tracks/{}", id),
+            "type": "audio_features",
+            "uri": format!("spotify:track:{}", id),
+            "valence": 0.5,
+        }))
+        .unwrap()
+    }
+}
@@ -0,0 +1,129 @@
+use color_eyre::{eyre::eyre, Result};
+use sled::Db;
+
+use crate::compression::Codec;
+
+/// `meta` key caching the number of tracks with audio features, removed by every write to
+/// `track_features` and recounted on the next read.
+const TRACKS_WITH_FEATURES_KEY: &str = "tracks_with_features";
+
+/// `meta` key holding the newest rating time of a feature, 0 if no rating carries one.
+fn last_rated_at_key(feature_name: &str) -> String {
+    format!("last_rated_at/{}", feature_name)
+}
+
+fn eight_bytes(bytes: &[u8]) -> Result<[u8; 8]> {
+    bytes
+        .try_into()
+        .map_err(|_| eyre!("malformed cached value of {} bytes", bytes.len()))
+}
+
+/// Number of tracks with audio features, leaving out the null markers stored for tracks
+/// Spotify has no analysis of.
+pub(crate) fn tracks_with_features(db: &Db) -> Result<usize> {
+    let meta_tree = db.open_tree("meta")?;
+    if let Some(bytes) = meta_tree.get(TRACKS_WITH_FEATURES_KEY)? {
+        return Ok(u64::from_be_bytes(eight_bytes(&bytes)?) as usize);
+    }
+    let codec = Codec::of(db)?;
+    let mut count = 0u64;
+    for it in db.open_tree("track_features")?.iter() {
+        let (_, features_bytes) = it?;
+        if &codec.decode(&features_bytes)?[..] != b"null" {
+            count += 1;
+        }
+    }
+    meta_tree.insert(TRACKS_WITH_FEATURES_KEY, &count.to_be_bytes())?;
+    Ok(count as usize)
+}
+
+/// Drops the cached [`tracks_with_features`] after `track_features` was written to.
+pub(crate) fn forget_tracks_with_features(db: &Db) -> Result<()> {
+    db.open_tree("meta")?.remove(TRACKS_WITH_FEATURES_KEY)?;
+    Ok(())
+}
+
+/// Unix timestamp of the newest rating of the feature, if any rating carries one.
+///
+/// Features rated before the time was kept here get it from a scan of their ratings once.
+pub(crate) fn last_rated_at(db: &Db, feature_name: &str) -> Result<Option<i64>> {
+    let meta_tree = db.open_tree("meta")?;
+    let key = last_rated_at_key(feature_name);
+    let last_rated_at = match meta_tree.get(&key)? {
+        Some(bytes) => i64::from_be_bytes(eight_bytes(&bytes)?),
+        None => {
+            let mut last_rated_at = 0;
+            for it in db.open_tree(format!("input/{}", feature_name))?.iter() {
+                let (_, rating) = it?;
+                last_rated_at =
+                    last_rated_at.max(crate::learning::Rating::decode(&rating)?.rated_at);
+            }
+            meta_tree.insert(&key, &last_rated_at.to_be_bytes())?;
+            last_rated_at
+        }
+    };
+    Ok(Some(last_rated_at).filter(|&rated_at| rated_at > 0))
+}
+
+/// Keeps [`last_rated_at`] up to date with a rating just stored for the feature.
+pub(crate) fn record_rated_at(db: &Db, feature_name: &str, rated_at: i64) -> Result<()> {
+    db.open_tree("meta")?
+        .fetch_and_update(last_rated_at_key(feature_name), |old| {
+            let old = old
+                .and_then(|bytes| eight_bytes(bytes).ok())
+                .map_or(0, i64::from_be_bytes);
+            Some(old.max(rated_at).to_be_bytes().to_vec())
+        })?;
+    Ok(())
+}
+
+/// Drops the cached [`last_rated_at`] of a feature whose ratings were removed or moved.
+pub(crate) fn forget_rated_at(db: &Db, feature_name: &str) -> Result<()> {
+    db.open_tree("meta")?
+        .remove(last_rated_at_key(feature_name))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{learning::Rating, tests::temporary_db};
+
+    #[test]
+    fn tracks_without_analysis_are_not_counted() {
+        let db = temporary_db();
+        let features_tree = db.open_tree("track_features").unwrap();
+        features_tree
+            .insert("track1", "{\"danceability\":0.5}")
+            .unwrap();
+        features_tree.insert("track2", "null").unwrap();
+        assert_eq!(tracks_with_features(&db).unwrap(), 1);
+        features_tree
+            .insert("track3", "{\"danceability\":0.7}")
+            .unwrap();
+        forget_tracks_with_features(&db).unwrap();
+        assert_eq!(tracks_with_features(&db).unwrap(), 2);
+    }
+
+    #[test]
+    fn last_rated_at_follows_new_ratings() {
+        let db = temporary_db();
+        let feature_tree = db.open_tree("input/comfy").unwrap();
+        let legacy = Rating {
+            rating: 1,
+            rated_at: 0,
+        };
+        feature_tree.insert("track1", &legacy.encode()).unwrap();
+        assert_eq!(last_rated_at(&db, "comfy").unwrap(), None);
+        let rated = Rating {
+            rating: 1,
+            rated_at: 1_600_000_000,
+        };
+        feature_tree.insert("track2", &rated.encode()).unwrap();
+        record_rated_at(&db, "comfy", rated.rated_at).unwrap();
+        record_rated_at(&db, "comfy", 1_500_000_000).unwrap();
+        assert_eq!(last_rated_at(&db, "comfy").unwrap(), Some(1_600_000_000));
+        forget_rated_at(&db, "comfy").unwrap();
+        assert_eq!(last_rated_at(&db, "comfy").unwrap(), Some(1_600_000_000));
+    }
+}
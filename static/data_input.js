@@ -21,13 +21,26 @@ async function build() {
             header.innerText = feature_name;
             body.appendChild(header);
             const track = document.createElement("p");
+            const preview = document.createElement("audio");
+            preview.controls = true;
             async function reloadRandom() {
-                const details = (await (await fetch(`/api/features/${feature_name}/tracks/random_untrained`)).json());
+                const response = await fetch(`/api/features/${feature_name}/tracks/random_untrained`);
+                const details = await response.json();
+                if (!response.ok) {
+                    track.innerText = details.error;
+                    delete body.dataset.id;
+                    return;
+                }
                 const artists = details.artists.map(artist => artist.name).join(", ");
                 track.innerText = `${artists} – ${details.name}`;
                 body.dataset.id = details.id;
-                const uri = `spotify:track:${details.id}`;
-                fetch("https://api.spotify.com/v1/me/player/play", { method: "PUT", headers: { "Authorization": `Bearer ${spotify_token}`, "Content-Type": "application/json" }, body: JSON.stringify({ "uris": [uri] }) });
+                preview.pause();
+                if (details.preview_url !== null) {
+                    preview.src = details.preview_url;
+                    preview.play();
+                } else {
+                    fetch("https://api.spotify.com/v1/me/player/play", { method: "PUT", headers: { "Authorization": `Bearer ${spotify_token}`, "Content-Type": "application/json" }, body: JSON.stringify({ "uris": [details.play_uri] }) });
+                }
             }
             async function rateAndReload(rating) {
                 await fetch(`/api/features/${feature_name}/tracks/${body.dataset.id}/rate/${rating}`, { method: "POST" });
@@ -39,10 +52,18 @@ async function build() {
             const upvote = document.createElement("button");
             upvote.innerText = "1";
             upvote.addEventListener("click", () => rateAndReload(1));
+            const skip = document.createElement("button");
+            skip.innerText = "skip";
+            skip.addEventListener("click", async () => {
+                await fetch(`/api/features/${feature_name}/tracks/${body.dataset.id}/skip`, { method: "POST" });
+                await reloadRandom();
+            });
             body.appendChild(downvote);
             body.appendChild(upvote);
+            body.appendChild(skip);
             body.appendChild(document.createElement("br"));
             body.appendChild(track);
+            body.appendChild(preview);
             await reloadRandom();
         });
         body.appendChild(create_button);